@@ -0,0 +1,241 @@
+use crate::config::{AttributeConfig, AttributeSource};
+use anyhow::{bail, ensure, Context, Result};
+
+// Parse a `NAME=VALUE` attribute from the command line.
+pub fn parse_attribute(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .filter(|(name, _)| !name.is_empty())
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .ok_or_else(|| format!("`{s}` is not of the form NAME=VALUE"))
+}
+
+fn find<'a>(attributes: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    attributes
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, value)| value.as_str())
+}
+
+// Names of all the attributes that need user input and are not in `provided`.
+pub fn missing_attributes<'a>(
+    configs: &'a [AttributeConfig],
+    provided: &[(String, String)],
+) -> Vec<&'a str> {
+    configs
+        .iter()
+        .filter(|config| {
+            matches!(
+                config.source,
+                AttributeSource::Prompt | AttributeSource::Select { .. }
+            )
+        })
+        .filter(|config| find(provided, &config.name).is_none())
+        .map(|config| config.name.as_str())
+        .collect()
+}
+
+// Make sure that all provided values are valid options for attributes that
+// are a selection from a list.
+pub fn validate_attributes(
+    configs: &[AttributeConfig],
+    provided: &[(String, String)],
+) -> Result<()> {
+    for config in configs {
+        if let (AttributeSource::Select { options }, Some(value)) =
+            (&config.source, find(provided, &config.name))
+        {
+            ensure!(
+                options.iter().any(|option| option == value),
+                "invalid value `{value}` for attribute `{}` (possible values: {})",
+                config.name,
+                options.join(", ")
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// Return the final list of attributes for the entry of a run. Provided values
+// always take precedence over values derived from the run.
+pub fn resolve_attributes(
+    configs: &[AttributeConfig],
+    provided: &[(String, String)],
+    run_number: u32,
+    odb: &serde_json::Value,
+) -> Result<Vec<(String, String)>> {
+    let mut attributes = Vec::new();
+    for config in configs {
+        let value = if let Some(value) = find(provided, &config.name) {
+            value.to_string()
+        } else {
+            match &config.source {
+                AttributeSource::Prompt | AttributeSource::Select { .. } => {
+                    bail!("missing value for attribute `{}`", config.name)
+                }
+                AttributeSource::Odb { pointer, if_empty } => {
+                    let value = odb
+                        .pointer(pointer)
+                        .with_context(|| format!("failed to find `{pointer}` in the ODB"))?;
+                    let value = match value {
+                        serde_json::Value::String(s) => s.clone(),
+                        value => value.to_string(),
+                    };
+
+                    match if_empty {
+                        Some(default) if value.is_empty() => default.clone(),
+                        _ => value,
+                    }
+                }
+                AttributeSource::RunNumber => run_number.to_string(),
+            }
+        };
+        attributes.push((config.name.clone(), value));
+    }
+    // Anything that was provided but is not in the configuration is just
+    // passed through as is.
+    for (name, value) in provided {
+        if !configs.iter().any(|config| &config.name == name) {
+            attributes.push((name.clone(), value.clone()));
+        }
+    }
+
+    Ok(attributes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[derive(serde::Deserialize)]
+    struct Profile {
+        attributes: Vec<AttributeConfig>,
+    }
+
+    fn configs() -> Vec<AttributeConfig> {
+        let profile: Profile = toml::from_str(
+            r#"
+attributes = [
+    { name = "Author", source = "prompt" },
+    { name = "Type", source = "select", options = ["Pbar Log", "Positron Log"] },
+    { name = "Run", source = "run_number" },
+    { name = "Subject", source = "odb", pointer = "/Experiment/Comment", if_empty = "MISSING COMMENT" },
+    { name = "Shots", source = "odb", pointer = "/Experiment/Shots" },
+]
+"#,
+        )
+        .unwrap();
+
+        profile.attributes
+    }
+
+    fn provided(attributes: &[(&str, &str)]) -> Vec<(String, String)> {
+        attributes
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn attributes_are_parsed_from_the_command_line() {
+        assert_eq!(
+            parse_attribute("Author=A = B").unwrap(),
+            (String::from("Author"), String::from("A = B"))
+        );
+        assert_eq!(
+            parse_attribute("Subject=").unwrap(),
+            (String::from("Subject"), String::new())
+        );
+        assert!(parse_attribute("Author").is_err());
+        assert!(parse_attribute("=value").is_err());
+    }
+
+    #[test]
+    fn only_user_input_can_be_missing() {
+        let configs = configs();
+        assert_eq!(missing_attributes(&configs, &[]), ["Author", "Type"]);
+        assert_eq!(
+            missing_attributes(&configs, &provided(&[("Type", "Pbar Log")])),
+            ["Author"]
+        );
+    }
+
+    #[test]
+    fn selections_must_be_valid_options() {
+        let configs = configs();
+        assert!(validate_attributes(&configs, &provided(&[("Type", "Pbar Log")])).is_ok());
+        // Free text can be anything.
+        assert!(validate_attributes(&configs, &provided(&[("Author", "Pbar Log")])).is_ok());
+        let err = validate_attributes(&configs, &provided(&[("Type", "Pbar log")])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid value `Pbar log` for attribute `Type` (possible values: Pbar Log, Positron Log)"
+        );
+    }
+
+    #[test]
+    fn attributes_are_resolved_from_the_run() {
+        let configs = configs();
+        let values = provided(&[("Author", "Someone"), ("Type", "Pbar Log")]);
+        let odb = json!({ "Experiment": { "Comment": "Hot dumps", "Shots": 3 } });
+
+        assert_eq!(
+            resolve_attributes(&configs, &values, 12001, &odb).unwrap(),
+            provided(&[
+                ("Author", "Someone"),
+                ("Type", "Pbar Log"),
+                ("Run", "12001"),
+                ("Subject", "Hot dumps"),
+                ("Shots", "3"),
+            ])
+        );
+    }
+
+    #[test]
+    fn empty_odb_values_use_the_fallback() {
+        let configs = configs();
+        let provided = provided(&[("Author", "Someone"), ("Type", "Pbar Log")]);
+        let odb = json!({ "Experiment": { "Comment": "", "Shots": "" } });
+
+        let attributes = resolve_attributes(&configs, &provided, 12001, &odb).unwrap();
+        assert_eq!(find(&attributes, "Subject"), Some("MISSING COMMENT"));
+        // No fallback configured.
+        assert_eq!(find(&attributes, "Shots"), Some(""));
+    }
+
+    #[test]
+    fn provided_values_take_precedence() {
+        let configs = configs();
+        let provided = provided(&[
+            ("Author", "Someone"),
+            ("Type", "Pbar Log"),
+            ("Run", "1"),
+            ("Subject", "Overridden"),
+            ("Shots", "0"),
+            ("Extra", "Passed through"),
+        ]);
+
+        assert_eq!(
+            resolve_attributes(&configs, &provided, 12001, &json!({})).unwrap(),
+            provided
+        );
+    }
+
+    #[test]
+    fn unresolved_attributes_are_errors() {
+        let configs = configs();
+        let odb = json!({ "Experiment": { "Comment": "Hot dumps", "Shots": 3 } });
+
+        let err = resolve_attributes(&configs, &provided(&[("Type", "Pbar Log")]), 12001, &odb)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "missing value for attribute `Author`");
+
+        let provided = provided(&[("Author", "Someone"), ("Type", "Pbar Log")]);
+        let err = resolve_attributes(&configs, &provided, 12001, &json!({})).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "failed to find `/Experiment/Comment` in the ODB"
+        );
+    }
+}
@@ -0,0 +1,134 @@
+use crate::config::CacheConfig;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+#[derive(Clone, Copy, Debug)]
+pub enum CacheMode {
+    Enabled,
+    // Ignore whatever is in the cache, but still store new responses.
+    Refresh,
+    Disabled,
+}
+
+// Responses from the data handler stored on disk. Each file is named after the
+// run number and a hash of the request that produced it.
+//
+// Failing to read from or write to the cache is never an error; the response
+// is simply requested from the data handler again.
+#[derive(Clone)]
+pub struct Cache {
+    dir: PathBuf,
+    read: bool,
+}
+
+// Long running processes (e.g. `watch`) open the cache over and over again.
+// Scanning the whole directory every single time is a waste.
+const EVICTION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+static LAST_EVICTION: Mutex<Option<Instant>> = Mutex::new(None);
+
+impl Cache {
+    // Open the cache at `dir` (creating it if needed) and evict old entries.
+    // Returns `None` if the cache is disabled (or the directory can't be
+    // created).
+    pub fn open(dir: PathBuf, mode: CacheMode, config: &CacheConfig) -> Option<Self> {
+        let read = match mode {
+            CacheMode::Enabled => true,
+            CacheMode::Refresh => false,
+            CacheMode::Disabled => return None,
+        };
+        std::fs::create_dir_all(&dir).ok()?;
+
+        let mut last_eviction = LAST_EVICTION.lock().unwrap();
+        if last_eviction.is_none_or(|last| last.elapsed() >= EVICTION_INTERVAL) {
+            evict(
+                &dir,
+                Duration::from_secs(config.max_age_days * 24 * 60 * 60),
+                config.max_size_mb * 1024 * 1024,
+            );
+            *last_eviction = Some(Instant::now());
+        }
+
+        Some(Self { dir, read })
+    }
+
+    fn path(&self, run_number: u32, key: &str) -> PathBuf {
+        self.dir
+            .join(format!("{run_number}-{:016x}", fnv1a(key.as_bytes())))
+    }
+
+    pub fn get(&self, run_number: u32, key: &str) -> Option<Vec<u8>> {
+        if !self.read {
+            return None;
+        }
+
+        let path = self.path(run_number, key);
+        let bytes = std::fs::read(&path).ok()?;
+        // Eviction by size removes the least recently used entries first.
+        let _ = std::fs::File::options()
+            .append(true)
+            .open(&path)
+            .and_then(|file| file.set_modified(SystemTime::now()));
+
+        Some(bytes)
+    }
+
+    pub fn put(&self, run_number: u32, key: &str, bytes: &[u8]) {
+        // Write to a temporary file first so that a concurrent reader never
+        // sees a partially written entry.
+        let _ = tempfile::NamedTempFile::new_in(&self.dir).and_then(|mut temp| {
+            temp.write_all(bytes)?;
+            temp.persist(self.path(run_number, key))?;
+            Ok(())
+        });
+    }
+}
+
+// Remove all entries older than `max_age`, and then the least recently used
+// entries until the cache is at most `max_size` bytes.
+//
+// Other processes might be adding or evicting entries at the same time. Any
+// entry that can't be read or removed is just skipped.
+fn evict(dir: &Path, max_age: Duration, max_size: u64) {
+    let now = SystemTime::now();
+    let Ok(read_dir) = std::fs::read_dir(dir) else {
+        return;
+    };
+
+    let mut entries = Vec::new();
+    for entry in read_dir.flatten() {
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        let Ok(modified) = metadata.modified() else {
+            continue;
+        };
+        if !metadata.is_file() {
+            continue;
+        }
+        if now.duration_since(modified).unwrap_or_default() > max_age {
+            let _ = std::fs::remove_file(entry.path());
+        } else {
+            entries.push((modified, metadata.len(), entry.path()));
+        }
+    }
+
+    entries.sort_unstable();
+    let mut size = entries.iter().map(|(_, len, _)| len).sum::<u64>();
+    for (_, len, path) in entries {
+        if size <= max_size {
+            break;
+        }
+        if std::fs::remove_file(path).is_ok() {
+            size -= len;
+        }
+    }
+}
+
+// Hash that is stable across builds and platforms (unlike `DefaultHasher`).
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
@@ -1,4 +1,8 @@
-use serde::Deserialize;
+use anyhow::{Context, Result};
+use jiff::tz::TimeZone;
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
 use std::path::PathBuf;
 
 #[derive(Debug, Deserialize)]
@@ -7,45 +11,438 @@ pub struct Config {
     pub elog: ElogConfig,
     pub data_handler: DataHandlerConfig,
     pub rules: Vec<LogRule>,
+    #[serde(default)]
+    pub rule_matching: RuleMatching,
+    #[serde(default)]
+    pub sequencer_headers: SequencerHeaders,
+    #[serde(default)]
+    pub record_times: RecordTimes,
+    // Each format is a separate attachment.
+    #[serde(default = "default_summary_formats")]
+    pub summary_formats: Vec<SummaryFormat>,
+    // Timezone of the experiment (IANA name). All times in an entry are
+    // shown in this timezone.
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+    pub watch: Option<WatchConfig>,
+    #[serde(default)]
+    pub cache: CacheConfig,
+    // Only used with `--offline` (or if `offline.enabled`).
+    pub offline: Option<OfflineConfig>,
+}
+
+impl Config {
+    // Profile of the logbook that entries are submitted to.
+    pub fn profile(&self) -> Result<&LogbookProfile> {
+        self.elog
+            .logbooks
+            .get(&self.elog.logbook)
+            .with_context(|| format!("no profile configured for logbook `{}`", self.elog.logbook))
+    }
+
+    pub fn spill_log_columns(&self) -> Result<&[String]> {
+        Ok(self
+            .profile()?
+            .spill_log_columns
+            .as_deref()
+            .unwrap_or(&self.spill_log_columns))
+    }
+
+    pub fn rules(&self) -> Result<&[LogRule]> {
+        Ok(self.profile()?.rules.as_deref().unwrap_or(&self.rules))
+    }
+
+    pub fn time_zone(&self) -> Result<TimeZone> {
+        TimeZone::get(&self.timezone)
+            .with_context(|| format!("failed to get `{}` timezone", self.timezone))
+    }
+
+    pub fn summary_formats(&self) -> Result<&[SummaryFormat]> {
+        Ok(self
+            .profile()?
+            .summary_formats
+            .as_deref()
+            .unwrap_or(&self.summary_formats))
+    }
+
+    pub fn record_times(&self) -> Result<RecordTimes> {
+        Ok(self.profile()?.record_times.unwrap_or(self.record_times))
+    }
+
+    pub fn sequencer_headers(&self) -> Result<SequencerHeaders> {
+        Ok(self
+            .profile()?
+            .sequencer_headers
+            .unwrap_or(self.sequencer_headers))
+    }
+}
+
+// CERN
+fn default_timezone() -> String {
+    String::from("Europe/Zurich")
 }
 
 #[derive(Debug, Deserialize)]
 pub struct ElogConfig {
-    pub client: PathBuf,
     pub host: String,
     pub port: u16,
-    pub logbook: Logbook,
+    // Default logbook to submit entries to.
+    pub logbook: String,
+    // Key is the name of a logbook in the ELOG server.
+    #[serde(default = "default_logbooks")]
+    pub logbooks: HashMap<String, LogbookProfile>,
 }
 
+// The `DataLog` and `test` logbooks of the ALPHA-g ELOG server.
+fn default_logbooks() -> HashMap<String, LogbookProfile> {
+    let profile = |attributes| LogbookProfile {
+        attributes,
+        spill_log_columns: None,
+        rules: None,
+        sequencer_headers: None,
+        record_times: None,
+        summary_formats: None,
+    };
+    let author = AttributeConfig {
+        name: String::from("Author"),
+        source: AttributeSource::Prompt,
+    };
+
+    HashMap::from([
+        (
+            String::from("DataLog"),
+            profile(vec![
+                author.clone(),
+                AttributeConfig {
+                    name: String::from("Type"),
+                    source: AttributeSource::Select {
+                        options: [
+                            "Baseline Log",
+                            "Pbar Log",
+                            "Trapping Series",
+                            "Electron Log",
+                            "Positron Log",
+                        ]
+                        .map(String::from)
+                        .to_vec(),
+                    },
+                },
+                AttributeConfig {
+                    name: String::from("Run"),
+                    source: AttributeSource::RunNumber,
+                },
+                AttributeConfig {
+                    name: String::from("Subject"),
+                    source: AttributeSource::Odb {
+                        pointer: String::from("/Experiment/Edit on start/Comment"),
+                        if_empty: Some(String::from("MISSING START-RUN COMMENT")),
+                    },
+                },
+            ]),
+        ),
+        (String::from("test"), profile(vec![author])),
+    ])
+}
+
+// How entries are created for a particular logbook. The spill log columns,
+// rules, sequencer headers, record times and summary formats are taken from the
+// top level configuration unless overridden here.
 #[derive(Debug, Deserialize)]
-pub enum Logbook {
-    DataLog,
-    #[serde(rename = "test")]
-    Test,
+pub struct LogbookProfile {
+    #[serde(deserialize_with = "non_empty")]
+    pub attributes: Vec<AttributeConfig>,
+    pub spill_log_columns: Option<Vec<String>>,
+    pub rules: Option<Vec<LogRule>>,
+    pub sequencer_headers: Option<SequencerHeaders>,
+    pub record_times: Option<RecordTimes>,
+    pub summary_formats: Option<Vec<SummaryFormat>>,
 }
 
-impl std::fmt::Display for Logbook {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self {
-            Logbook::DataLog => write!(f, "DataLog"),
-            Logbook::Test => write!(f, "test"),
-        }
+// The ELOG server happily accepts entries without any attributes, which is
+// never what we want.
+fn non_empty<'de, D>(deserializer: D) -> Result<Vec<AttributeConfig>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let attributes = Vec::deserialize(deserializer)?;
+    if attributes.is_empty() {
+        return Err(serde::de::Error::custom(
+            "a logbook profile needs at least one attribute",
+        ));
     }
+
+    Ok(attributes)
 }
 
-#[derive(Debug, Deserialize)]
+// Timing information shown for each spill log record (both in the entry and
+// in the spill log summary).
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+pub struct RecordTimes {
+    // Local time instead of seconds since the start of the run.
+    #[serde(default)]
+    pub wall_clock: bool,
+    #[serde(default)]
+    pub duration: bool,
+    // Time since the end of the previous record.
+    #[serde(default)]
+    pub gap: bool,
+}
+
+// Format of the spill log summary attachment.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SummaryFormat {
+    // Plain text table.
+    Text,
+    Csv,
+    Html,
+    Markdown,
+}
+
+fn default_summary_formats() -> Vec<SummaryFormat> {
+    vec![SummaryFormat::Text]
+}
+
+// How the sequences loaded during a run are included in its entry.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SequencerHeaders {
+    #[default]
+    None,
+    // A single text attachment with the headers of all sequences.
+    Text,
+    // One XML attachment per sequence.
+    Xml,
+    // A table with the serial number, time and header of each sequence.
+    Summary,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct AttributeConfig {
+    pub name: String,
+    #[serde(flatten)]
+    pub source: AttributeSource,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "source", rename_all = "snake_case")]
+pub enum AttributeSource {
+    // Free text asked to the user.
+    Prompt,
+    // One of a fixed list of options chosen by the user.
+    Select {
+        options: Vec<String>,
+    },
+    // Value found in the final ODB at the given JSON pointer.
+    Odb {
+        pointer: String,
+        if_empty: Option<String>,
+    },
+    RunNumber,
+}
+
+#[derive(Clone, Debug, Deserialize)]
 pub struct DataHandlerConfig {
     pub host: String,
     pub port: u16,
+    // Maximum number of plots requested at the same time.
+    #[serde(default = "default_max_concurrent_requests")]
+    pub max_concurrent_requests: usize,
+    // All timeouts are in seconds. The `read_timeout` is the longest the data
+    // handler can stay silent, and `timeout` is the longest a single request
+    // can take.
+    #[serde(default = "default_connect_timeout")]
+    pub connect_timeout: u64,
+    #[serde(default = "default_read_timeout")]
+    pub read_timeout: u64,
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    // Number of times a request is retried after a transient failure (e.g.
+    // connection refused). The wait between attempts doubles every time.
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    #[serde(default = "default_initial_backoff_ms")]
+    pub initial_backoff_ms: u64,
+    // With `--wait`, how often (in seconds) to ask the data handler whether a
+    // run is ready, and how long to keep asking before giving up.
+    #[serde(default = "default_wait_poll_interval")]
+    pub wait_poll_interval: u64,
+    #[serde(default = "default_wait_deadline")]
+    pub wait_deadline: u64,
+}
+
+fn default_max_concurrent_requests() -> usize {
+    4
+}
+
+fn default_connect_timeout() -> u64 {
+    10
+}
+
+fn default_read_timeout() -> u64 {
+    120
+}
+
+fn default_timeout() -> u64 {
+    600
+}
+
+fn default_max_retries() -> u32 {
+    3
+}
+
+fn default_initial_backoff_ms() -> u64 {
+    500
+}
+
+fn default_wait_poll_interval() -> u64 {
+    10
+}
+
+fn default_wait_deadline() -> u64 {
+    1800
+}
+
+// Local cache of data handler responses. Entries that have not been used for
+// `max_age_days` are removed, and then the least recently used ones until the
+// whole cache is at most `max_size_mb`.
+#[derive(Debug, Deserialize)]
+pub struct CacheConfig {
+    #[serde(default = "default_cache_max_age_days")]
+    pub max_age_days: u64,
+    #[serde(default = "default_cache_max_size_mb")]
+    pub max_size_mb: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            max_age_days: default_cache_max_age_days(),
+            max_size_mb: default_cache_max_size_mb(),
+        }
+    }
+}
+
+fn default_cache_max_age_days() -> u64 {
+    30
+}
+
+fn default_cache_max_size_mb() -> u64 {
+    1024
+}
+
+// Local files used instead of the data handler. Any `{run_number}` in a path
+// is replaced by the run being logged. Chronobox plots are looked up in
+// `plots_dir` as `<board>-<channel>-<t_min>-<t_max>.pdf` (e.g.
+// `cb01-5-12.5-30.25.pdf`).
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct OfflineConfig {
+    // Always use the local files (same as `--offline`).
+    #[serde(default)]
+    pub enabled: bool,
+    pub final_odb: Option<PathBuf>,
+    pub spill_log: Option<PathBuf>,
+    pub sequencer_csv: Option<PathBuf>,
+    pub plots_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WatchConfig {
+    // Values of the attributes that would otherwise be asked to the user.
+    pub attributes: HashMap<String, String>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct LogRule {
-    pub sequencer_name: String,
-    pub event_description: String,
+    pub sequencer_name: Pattern,
+    pub event_description: Pattern,
     pub config: EntryConfig,
 }
 
+// What to do when multiple rules match the same spill log record.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleMatching {
+    // Use the first matching rule (in configuration order).
+    #[default]
+    First,
+    // Use the matching rule with the most specific patterns (exact strings are
+    // more specific than globs, which are more specific than regexes). Ties
+    // are resolved in configuration order.
+    MostSpecific,
+    // Merge the configuration of all matching rules.
+    Merge,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PatternConfig {
+    Exact(String),
+    Glob { glob: String },
+    Regex { regex: String },
+}
+
+// A plain string in the configuration file is matched exactly. Otherwise it
+// can be either `{ glob = "..." }` (where `*` and `?` are wildcards) or
+// `{ regex = "..." }`. Patterns always have to match the whole string.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "PatternConfig")]
+pub struct Pattern {
+    regex: Regex,
+    specificity: u8,
+}
+
+impl TryFrom<PatternConfig> for Pattern {
+    type Error = regex::Error;
+
+    fn try_from(config: PatternConfig) -> Result<Self, Self::Error> {
+        let (regex, specificity) = match config {
+            PatternConfig::Exact(s) => (regex::escape(&s), 2),
+            // Each wildcard is a capture group (same as in a regex).
+            PatternConfig::Glob { glob } => (
+                glob.chars()
+                    .map(|c| match c {
+                        '*' => String::from("(.*)"),
+                        '?' => String::from("(.)"),
+                        c => regex::escape(&c.to_string()),
+                    })
+                    .collect(),
+                1,
+            ),
+            PatternConfig::Regex { regex } => (regex, 0),
+        };
+
+        Ok(Self {
+            regex: Regex::new(&format!("^(?:{regex})$"))?,
+            specificity,
+        })
+    }
+}
+
+impl Pattern {
+    // Return all groups captured when matching `haystack`. Groups are keyed by
+    // their index, and also by their name if they have one. Groups that didn't
+    // participate in the match are empty.
+    pub fn captures(&self, haystack: &str) -> Option<HashMap<String, String>> {
+        let caps = self.regex.captures(haystack)?;
+
+        let mut groups = HashMap::new();
+        for (i, name) in self.regex.capture_names().enumerate().skip(1) {
+            let value = caps.get(i).map_or("", |m| m.as_str()).to_string();
+            if let Some(name) = name {
+                groups.insert(name.to_string(), value.clone());
+            }
+            groups.insert(i.to_string(), value);
+        }
+
+        Some(groups)
+    }
+
+    pub fn specificity(&self) -> u8 {
+        self.specificity
+    }
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct EntryConfig {
     pub chronobox_table: Option<ChronoboxTableConfig>,
@@ -62,10 +459,98 @@ pub struct ChronoboxTableConfig {
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct ExternalResourceConfig {
-    pub base_path: PathBuf,
+    #[serde(flatten)]
+    pub source: ResourceSource,
     pub header: Option<String>,
     #[serde(default)]
     pub include_description: bool,
     #[serde(default)]
     pub include_attachment: bool,
 }
+
+// Where the files of an external resource are found. By default these are
+// `<base_path>/<year>/<month>/<day>/hhmm_ss.mss.png`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Hash)]
+pub struct ResourceSource {
+    pub base_path: PathBuf,
+    #[serde(default)]
+    pub directory: DirectoryTemplate,
+    #[serde(default)]
+    pub file_pattern: FilePattern,
+    // Files are matched to the record with the closest time window. A file
+    // more than this many seconds away from all records is left unassigned.
+    #[serde(default = "default_resource_tolerance")]
+    pub tolerance: u64,
+}
+
+fn default_resource_tolerance() -> u64 {
+    10
+}
+
+// Directory (relative to `base_path`) with all the files of a given day. This
+// is a `strftime` template e.g. `%Y/%m/%d`, so it can only use date specifiers.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Hash)]
+#[serde(try_from = "String")]
+pub struct DirectoryTemplate(String);
+
+impl Default for DirectoryTemplate {
+    fn default() -> Self {
+        Self(String::from("%Y/%m/%d"))
+    }
+}
+
+impl TryFrom<String> for DirectoryTemplate {
+    type Error = String;
+
+    fn try_from(template: String) -> Result<Self, Self::Error> {
+        let template = Self(template);
+        template
+            .format(jiff::civil::date(2024, 1, 1))
+            .ok_or_else(|| format!("`{}` is not a valid date template", template.0))?;
+
+        Ok(template)
+    }
+}
+
+impl DirectoryTemplate {
+    // `None` if the template can't be formatted for the given date.
+    pub fn format(&self, date: jiff::civil::Date) -> Option<String> {
+        use std::fmt::Write;
+
+        let mut dir = String::new();
+        write!(dir, "{}", date.strftime(&self.0)).ok()?;
+        Some(dir)
+    }
+}
+
+// Regex that the whole file name has to match. The time of a file is taken
+// from the named groups `year`, `month`, `day`, `hour`, `minute`, `second` and
+// `millisecond` (the date defaults to the day of the directory, and the time
+// fields to zero). Alternatively, a `timestamp` group is the number of seconds
+// since the Unix epoch.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Hash)]
+#[serde(try_from = "String")]
+pub struct FilePattern(String);
+
+impl Default for FilePattern {
+    fn default() -> Self {
+        Self(String::from(
+            r"(?<hour>\d{2})(?<minute>\d{2})_(?<second>\d{2})\.(?<millisecond>\d{3})\.png",
+        ))
+    }
+}
+
+impl TryFrom<String> for FilePattern {
+    type Error = regex::Error;
+
+    fn try_from(pattern: String) -> Result<Self, Self::Error> {
+        Regex::new(&pattern)?;
+        Ok(Self(pattern))
+    }
+}
+
+impl FilePattern {
+    pub fn regex(&self) -> Regex {
+        Regex::new(&format!("^(?:{})$", self.0)).unwrap()
+    }
+}
@@ -1,12 +1,17 @@
-use crate::config::DataHandlerConfig;
-use anyhow::{bail, ensure, Context, Result};
-use reqwest::blocking::Response;
+use crate::cache::Cache;
+use crate::config::{DataHandlerConfig, OfflineConfig};
+use anyhow::{anyhow, ensure, Context, Result};
+use indicatif::ProgressBar;
+use reqwest::blocking::Client;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::Write;
+use std::net::{TcpStream, ToSocketAddrs};
 use std::path::PathBuf;
+use std::time::{Duration, Instant};
 use tempfile::Builder;
-use tungstenite::client::connect;
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::WebSocket;
 
 #[derive(Serialize)]
 struct ClientMessage {
@@ -15,7 +20,7 @@ struct ClientMessage {
     request: ClientRequest,
 }
 
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
 enum ClientRequest {
     ChronoboxPlot {
         run_number: u32,
@@ -32,9 +37,39 @@ enum ClientRequest {
     },
 }
 
+impl ClientRequest {
+    fn run_number(&self) -> u32 {
+        match self {
+            ClientRequest::ChronoboxPlot { run_number, .. }
+            | ClientRequest::FinalOdb { run_number }
+            | ClientRequest::SequencerCsv { run_number }
+            | ClientRequest::SpillLog { run_number } => *run_number,
+        }
+    }
+
+    // Key of the response in the cache.
+    fn cache_key(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    // Short description shown next to the progress messages of a request.
+    fn label(&self) -> String {
+        match self {
+            ClientRequest::ChronoboxPlot { args, .. } => format!(
+                "Chronobox plot ({} channel {})",
+                args.board_name, args.channel_number
+            ),
+            ClientRequest::FinalOdb { .. } => String::from("Final ODB"),
+            ClientRequest::SequencerCsv { .. } => String::from("Sequencer CSV"),
+            ClientRequest::SpillLog { .. } => String::from("Spill log"),
+        }
+    }
+}
+
+// The data handler echoes back the `service` and `context` of the request that
+// a message is replying to.
 #[derive(Deserialize)]
 struct ServerMessage {
-    service: String,
     context: String,
     response: ServerResponse,
 }
@@ -46,64 +81,87 @@ enum ServerResponse {
     DownloadJWT(String),
 }
 
-// The ALPHA-g Data Handler doesn't (yet) have a stable public API. To get any
-// data/plots out of it, it is important that we just reproduce whatever its
-// internal web browser client does:
-// Make sure that a GET request to the `/:run_number` endpoint returns a
-// successful response before requesting anything via websockets.
-// This is VERY IMPORTANT (due to some internal state management). Otherwise, we
-// would be forcing the server to cache incorrect data.
-fn is_data_handler_ready(run_number: u32, config: &DataHandlerConfig) -> Result<bool> {
-    Ok(reqwest::blocking::get(format!(
-        "http://{}:{}/{run_number}",
-        config.host, config.port
-    ))
-    .with_context(|| format!("failed GET request to data handler's `/{run_number}` endpoint"))?
-    .status()
-    .is_success())
-}
-
-fn ws_request(request: ClientRequest, config: &DataHandlerConfig) -> Result<Response> {
-    let msg = ClientMessage {
-        service: String::new(),
-        context: String::new(),
-        request,
-    };
-    let msg = tungstenite::Message::Text(serde_json::to_string(&msg)?);
+#[derive(Clone, Debug)]
+pub enum DataHandlerError {
+    // The data handler can't serve data for the run (yet).
+    NotReady,
+    // The data handler didn't reply in time.
+    TimedOut,
+    // The data handler replied with an HTTP server error (5xx status).
+    Server(String),
+    // The data handler reported an internal error while handling a request.
+    Internal(String),
+    // Failed to connect to the data handler, or the connection was lost.
+    Connection(String),
+}
 
-    let (mut ws, _) = connect(format!("ws://{}:{}/ws", config.host, config.port))
-        .context("failed to connect to data handler websocket")?;
-    ws.send(msg)
-        .context("failed to send websocket request to data handler")?;
+impl std::fmt::Display for DataHandlerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DataHandlerError::NotReady => write!(f, "data handler is not ready"),
+            DataHandlerError::TimedOut => write!(f, "timed out waiting for the data handler"),
+            DataHandlerError::Server(err) => write!(f, "data handler server error: `{err}`"),
+            DataHandlerError::Internal(err) => write!(f, "data handler internal error: `{err}`"),
+            DataHandlerError::Connection(err) => {
+                write!(f, "data handler connection error: `{err}`")
+            }
+        }
+    }
+}
 
-    let jwt = loop {
-        if let tungstenite::Message::Text(msg) =
-            ws.read().context("failed to read data handler message")?
-        {
-            let msg: ServerMessage =
-                serde_json::from_str(&msg).context("failed to parse data handler message")?;
-
-            match msg.response {
-                ServerResponse::DownloadJWT(jwt) => break jwt,
-                ServerResponse::Text(_) => continue,
-                ServerResponse::Error(err) => {
-                    bail!("data handler internal error: `{err}`");
-                }
+impl std::error::Error for DataHandlerError {}
+
+impl From<reqwest::Error> for DataHandlerError {
+    fn from(err: reqwest::Error) -> Self {
+        if err.is_timeout() {
+            DataHandlerError::TimedOut
+        } else if let Some(status) = err.status() {
+            DataHandlerError::Server(status.to_string())
+        } else {
+            DataHandlerError::Connection(format!("{:#}", anyhow!(err)))
+        }
+    }
+}
+
+impl From<tungstenite::Error> for DataHandlerError {
+    fn from(err: tungstenite::Error) -> Self {
+        match err {
+            tungstenite::Error::Io(err)
+                if matches!(
+                    err.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                DataHandlerError::TimedOut
             }
+            err => DataHandlerError::Connection(err.to_string()),
         }
-    };
+    }
+}
 
-    let resp = reqwest::blocking::get(format!(
-        "http://{}:{}/download/{jwt}",
-        config.host, config.port
-    ))
-    .context("failed GET request to data handler's download endpoint")?;
-    ensure!(
-        resp.status().is_success(),
-        "failed to download from data handler"
-    );
+// Connection problems and server errors are worth retrying. Everything else
+// would most likely just fail again.
+fn is_transient(err: &anyhow::Error) -> bool {
+    err.chain().any(|err| {
+        matches!(
+            err.downcast_ref::<DataHandlerError>(),
+            Some(DataHandlerError::Server(_) | DataHandlerError::Connection(_))
+        )
+    })
+}
 
-    Ok(resp)
+// `anyhow::Error` is not `Clone`. Copy an error that affects multiple requests
+// while keeping its `DataHandlerError` (if any), so that `is_transient` still
+// recognizes it.
+fn clone_error(err: &anyhow::Error) -> anyhow::Error {
+    match err
+        .chain()
+        .find_map(|err| err.downcast_ref::<DataHandlerError>())
+    {
+        Some(source) if err.is::<DataHandlerError>() => source.clone().into(),
+        Some(source) => anyhow::Error::new(source.clone()).context(err.to_string()),
+        None => anyhow!("{err:#}"),
+    }
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -121,102 +179,713 @@ pub struct SpillLog {
     pub records: Vec<Record>,
 }
 
-pub fn get_spill_log(run_number: u32, config: &DataHandlerConfig) -> Result<SpillLog> {
-    ensure!(
-        is_data_handler_ready(run_number, config).context("failed to query data handler state")?,
-        "data handler is not ready"
-    );
-
-    let resp = ws_request(ClientRequest::SpillLog { run_number }, config)
-        .context("failed to request spill log from data handler")?;
-    let records = csv::ReaderBuilder::new()
-        .comment(Some(b'#'))
-        .from_reader(resp)
-        .deserialize()
-        .collect::<Result<Vec<Record>, _>>()
-        .context("failed to parse spill log")?;
+// A sequence loaded into the sequencer during the run.
+#[derive(Debug, Deserialize)]
+pub struct SequencerRecord {
+    pub serial_number: u32,
+    // Seconds since the Unix epoch.
+    pub midas_timestamp: u32,
+    pub header: String,
+    pub xml: String,
+}
 
-    Ok(SpillLog { records })
+#[derive(Clone, Serialize)]
+pub struct ChronoboxTimestampsArgs {
+    pub board_name: String,
+    pub channel_number: u8,
+    pub t_bins: Option<u32>,
+    pub t_max: Option<f64>,
+    pub t_min: Option<f64>,
 }
 
-#[derive(Debug, Deserialize)]
-struct SequencerRecord {
-    serial_number: u32,
-    midas_timestamp: u32,
-    header: String,
-    xml: String,
+// A session with the data handler. All requests share a single websocket
+// connection, and the readiness of each run is only checked once.
+pub struct DataHandlerClient {
+    config: DataHandlerConfig,
+    http: Client,
+    ready_runs: HashSet<u32>,
+    ws: Option<WebSocket<MaybeTlsStream<TcpStream>>>,
+    next_context: u64,
+    // Replies (download JWT or error) that arrived while waiting for a
+    // different request. Key is the request context.
+    pending: HashMap<String, Result<String, String>>,
+    // Progress messages sent by the data handler while it prepares a download
+    // are shown here. Key of `labels` is the request context.
+    progress_bar: Option<ProgressBar>,
+    labels: HashMap<String, String>,
+    // Responses are only stored in the cache once the final ODB shows that
+    // the run has stopped. Anything else could still change.
+    cache: Option<Cache>,
+    stopped_runs: HashSet<u32>,
+    // Read everything from local files instead (the data handler is never
+    // contacted).
+    offline: Option<OfflineConfig>,
 }
 
-pub fn get_sequencer_headers(run_number: u32, config: &DataHandlerConfig) -> Result<PathBuf> {
-    ensure!(
-        is_data_handler_ready(run_number, config).context("failed to query data handler state")?,
-        "data handler is not ready"
-    );
+impl DataHandlerClient {
+    pub fn new(config: DataHandlerConfig) -> Result<Self> {
+        let http = Client::builder()
+            .connect_timeout(Duration::from_secs(config.connect_timeout))
+            .timeout(Duration::from_secs(config.timeout))
+            .build()
+            .context("failed to create HTTP client")?;
 
-    let resp = ws_request(ClientRequest::SequencerCsv { run_number }, config)
-        .context("failed to request sequencer CSV from data handler")?;
-    let records = csv::ReaderBuilder::new()
-        .comment(Some(b'#'))
-        .from_reader(resp)
-        .deserialize::<SequencerRecord>()
-        .map(|record| record.map(|record| record.header))
-        .collect::<Result<Vec<String>, _>>()
-        .context("failed to parse sequencer CSV")?
-        .join("\n\n\n");
+        Ok(Self {
+            config,
+            http,
+            ready_runs: HashSet::new(),
+            ws: None,
+            next_context: 0,
+            pending: HashMap::new(),
+            progress_bar: None,
+            labels: HashMap::new(),
+            cache: None,
+            stopped_runs: HashSet::new(),
+            offline: None,
+        })
+    }
 
-    let mut temp = Builder::new()
-        .keep(true)
-        .suffix(".txt")
-        .tempfile()
-        .context("failed to create temporary file")?;
-    temp.write_all(records.as_bytes())
-        .context("failed to write sequencer headers to temporary file")?;
+    pub fn set_progress_bar(&mut self, progress_bar: ProgressBar) {
+        self.progress_bar = Some(progress_bar);
+    }
 
-    Ok(temp.path().to_owned())
+    // Show a message in the progress bar (if any).
+    pub fn report(&self, message: &str) {
+        if let Some(progress_bar) = &self.progress_bar {
+            progress_bar.set_message(message.to_string());
+        }
+    }
+
+    pub fn set_cache(&mut self, cache: Cache) {
+        self.cache = Some(cache);
+    }
+
+    pub fn set_offline(&mut self, offline: OfflineConfig) {
+        self.offline = Some(offline);
+    }
+
+    fn cached(&self, request: &ClientRequest) -> Option<Vec<u8>> {
+        self.cache
+            .as_ref()?
+            .get(request.run_number(), &request.cache_key())
+    }
+
+    fn store(&self, request: &ClientRequest, bytes: &[u8]) {
+        if let Some(cache) = &self.cache {
+            if self.stopped_runs.contains(&request.run_number()) {
+                cache.put(request.run_number(), &request.cache_key(), bytes);
+            }
+        }
+    }
+
+    // Call `f` until it succeeds, it fails with a non-transient error, or we
+    // run out of retries. The wait between attempts doubles every time.
+    fn with_retries<T>(&mut self, mut f: impl FnMut(&mut Self) -> Result<T>) -> Result<T> {
+        let mut backoff = Duration::from_millis(self.config.initial_backoff_ms);
+        let mut retries = 0;
+        loop {
+            match f(self) {
+                Err(err) if retries < self.config.max_retries && is_transient(&err) => {
+                    std::thread::sleep(backoff);
+                    backoff *= 2;
+                    retries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // The ALPHA-g Data Handler doesn't (yet) have a stable public API. To get
+    // any data/plots out of it, it is important that we just reproduce
+    // whatever its internal web browser client does:
+    // Make sure that a GET request to the `/:run_number` endpoint returns a
+    // successful response before requesting anything via websockets.
+    // This is VERY IMPORTANT (due to some internal state management).
+    // Otherwise, we would be forcing the server to cache incorrect data.
+    pub fn is_ready(&mut self, run_number: u32) -> Result<bool> {
+        if self.offline.is_some() || self.ready_runs.contains(&run_number) {
+            return Ok(true);
+        }
+
+        let ready = self.with_retries(|client| {
+            let status = client
+                .http
+                .get(format!(
+                    "http://{}:{}/{run_number}",
+                    client.config.host, client.config.port
+                ))
+                .send()
+                .map_err(DataHandlerError::from)
+                .with_context(|| {
+                    format!("failed GET request to data handler's `/{run_number}` endpoint")
+                })?
+                .status();
+            if status.is_server_error() {
+                return Err(DataHandlerError::Server(status.to_string()))
+                    .with_context(|| format!("data handler's `/{run_number}` endpoint failed"));
+            }
+
+            Ok(status.is_success())
+        })?;
+        if ready {
+            self.ready_runs.insert(run_number);
+        }
+
+        Ok(ready)
+    }
+
+    // Poll the data handler until the run is ready, or fail if it is still not
+    // ready after the configured deadline.
+    pub fn wait_until_ready(&mut self, run_number: u32) -> Result<()> {
+        let poll_interval = Duration::from_secs(self.config.wait_poll_interval);
+        let deadline = Duration::from_secs(self.config.wait_deadline);
+        let start = Instant::now();
+
+        let mut next_poll = start;
+        loop {
+            let now = Instant::now();
+            if now >= next_poll {
+                if self
+                    .is_ready(run_number)
+                    .context("failed to query data handler state")?
+                {
+                    return Ok(());
+                }
+                next_poll = now + poll_interval;
+            }
+
+            let elapsed = start.elapsed();
+            if elapsed >= deadline {
+                return Err(DataHandlerError::NotReady).with_context(|| {
+                    format!(
+                        "gave up waiting for run {run_number} after {}",
+                        format_duration(elapsed)
+                    )
+                });
+            }
+            if let Some(progress_bar) = &self.progress_bar {
+                progress_bar.set_message(format!(
+                    "Waiting for the data handler to process run {run_number} ({} elapsed)...",
+                    format_duration(elapsed)
+                ));
+            }
+            // Wake up every second just to keep the elapsed time up to date.
+            std::thread::sleep(
+                Duration::from_secs(1)
+                    .min(next_poll.saturating_duration_since(Instant::now()))
+                    .min(deadline - elapsed),
+            );
+        }
+    }
+
+    fn ensure_ready(&mut self, run_number: u32) -> Result<()> {
+        if !self
+            .is_ready(run_number)
+            .context("failed to query data handler state")?
+        {
+            return Err(DataHandlerError::NotReady.into());
+        }
+
+        Ok(())
+    }
+
+    fn websocket(&mut self) -> Result<&mut WebSocket<MaybeTlsStream<TcpStream>>> {
+        if self.ws.is_none() {
+            let addr = (self.config.host.as_str(), self.config.port)
+                .to_socket_addrs()
+                .context("failed to resolve data handler address")?
+                .next()
+                .context("failed to resolve data handler address")?;
+            let stream =
+                TcpStream::connect_timeout(&addr, Duration::from_secs(self.config.connect_timeout))
+                    .map_err(|err| DataHandlerError::Connection(err.to_string()))
+                    .context("failed to connect to data handler websocket")?;
+            let (ws, _) = tungstenite::client::client(
+                format!("ws://{}:{}/ws", self.config.host, self.config.port),
+                MaybeTlsStream::Plain(stream),
+            )
+            .map_err(|err| DataHandlerError::Connection(err.to_string()))
+            .context("failed websocket handshake with data handler")?;
+            self.ws = Some(ws);
+        }
+
+        Ok(self.ws.as_mut().unwrap())
+    }
+
+    // Send a request and return the context that identifies its replies.
+    fn send(&mut self, request: ClientRequest) -> Result<String> {
+        let context = self.next_context.to_string();
+        self.next_context += 1;
+        self.labels.insert(context.clone(), request.label());
+
+        let msg = ClientMessage {
+            service: String::new(),
+            context: context.clone(),
+            request,
+        };
+        let msg = tungstenite::Message::Text(serde_json::to_string(&msg)?);
+
+        let result = self
+            .websocket()?
+            .send(msg)
+            .map_err(DataHandlerError::from)
+            .context("failed to send websocket request to data handler");
+        if result.is_err() {
+            // Start a new connection on the next request.
+            self.ws = None;
+        }
+        result.map(|_| context)
+    }
+
+    // Wait until the data handler replies with the download JWT (or an error)
+    // for any of the requests with the given contexts. Replies to other
+    // requests are kept until someone asks for them.
+    fn next_reply(
+        &mut self,
+        contexts: &[String],
+        deadline: Instant,
+    ) -> Result<(String, Result<String, String>)> {
+        let read_timeout = Duration::from_secs(self.config.read_timeout);
+        loop {
+            for context in contexts {
+                if let Some(reply) = self.pending.remove(context) {
+                    return Ok((context.clone(), reply));
+                }
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(DataHandlerError::TimedOut.into());
+            }
+            // Replies to requests sent over a previous connection will never
+            // arrive on a new one.
+            let ws = self.ws.as_mut().ok_or_else(|| {
+                DataHandlerError::Connection(String::from("connection to data handler was lost"))
+            })?;
+            if let MaybeTlsStream::Plain(stream) = ws.get_mut() {
+                stream
+                    .set_read_timeout(Some(read_timeout.min(remaining)))
+                    .context("failed to set websocket read timeout")?;
+            }
+
+            let msg = match ws.read() {
+                Ok(msg) => msg,
+                Err(err) => {
+                    let err = DataHandlerError::from(err);
+                    if !matches!(err, DataHandlerError::TimedOut) {
+                        self.ws = None;
+                    }
+                    return Err(err).context("failed to read data handler message");
+                }
+            };
+            match msg {
+                tungstenite::Message::Text(msg) => {
+                    let msg: ServerMessage = serde_json::from_str(&msg)
+                        .context("failed to parse data handler message")?;
+
+                    // Replies to requests that were already given up on
+                    // (i.e. without a label) are dropped.
+                    match msg.response {
+                        ServerResponse::DownloadJWT(jwt) => {
+                            if self.labels.remove(&msg.context).is_some() {
+                                self.pending.insert(msg.context, Ok(jwt));
+                            }
+                        }
+                        ServerResponse::Text(text) => {
+                            if let (Some(progress_bar), Some(label)) =
+                                (&self.progress_bar, self.labels.get(&msg.context))
+                            {
+                                progress_bar.set_message(format!("{label}: {}", text.trim()));
+                            }
+                        }
+                        ServerResponse::Error(err) => {
+                            if self.labels.remove(&msg.context).is_some() {
+                                self.pending.insert(msg.context, Err(err));
+                            }
+                        }
+                    }
+                }
+                tungstenite::Message::Close(_) => {
+                    self.ws = None;
+                    return Err(DataHandlerError::Connection(String::from(
+                        "websocket closed by data handler",
+                    ))
+                    .into());
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Forget about a request that will never be waited for again.
+    fn abandon(&mut self, context: &str) {
+        self.labels.remove(context);
+        self.pending.remove(context);
+    }
+
+    fn request(&mut self, request: ClientRequest) -> Result<Vec<u8>> {
+        if let Some(offline) = &self.offline {
+            let path = offline_path(offline, &request)?;
+            return std::fs::read(&path)
+                .with_context(|| format!("failed to read `{}`", path.display()));
+        }
+        if let Some(bytes) = self.cached(&request) {
+            return Ok(bytes);
+        }
+        self.ensure_ready(request.run_number())?;
+
+        let bytes = self.with_retries(|client| {
+            let deadline = Instant::now() + Duration::from_secs(client.config.timeout);
+            let context = client.send(request.clone())?;
+            let reply = client.next_reply(std::slice::from_ref(&context), deadline);
+            if reply.is_err() {
+                client.abandon(&context);
+            }
+            let (_, reply) = reply?;
+            let jwt = reply.map_err(DataHandlerError::Internal)?;
+
+            download(&client.http, &client.config, &jwt)
+        })?;
+        self.store(&request, &bytes);
+
+        Ok(bytes)
+    }
+
+    pub fn spill_log(&mut self, run_number: u32) -> Result<SpillLog> {
+        let resp = self
+            .request(ClientRequest::SpillLog { run_number })
+            .context("failed to request spill log from data handler")?;
+        let records = csv::ReaderBuilder::new()
+            .comment(Some(b'#'))
+            .from_reader(&resp[..])
+            .deserialize()
+            .collect::<Result<Vec<Record>, _>>()
+            .context("failed to parse spill log")?;
+
+        Ok(SpillLog { records })
+    }
+
+    pub fn sequencer_records(&mut self, run_number: u32) -> Result<Vec<SequencerRecord>> {
+        let resp = self
+            .request(ClientRequest::SequencerCsv { run_number })
+            .context("failed to request sequencer CSV from data handler")?;
+
+        csv::ReaderBuilder::new()
+            .comment(Some(b'#'))
+            .from_reader(&resp[..])
+            .deserialize()
+            .collect::<Result<Vec<SequencerRecord>, _>>()
+            .context("failed to parse sequencer CSV")
+    }
+
+    pub fn final_odb(&mut self, run_number: u32) -> Result<serde_json::Value> {
+        let request = ClientRequest::FinalOdb { run_number };
+        let resp = self
+            .request(request.clone())
+            .context("failed to request final ODB from data handler")?;
+        let text =
+            String::from_utf8(resp.clone()).context("failed to read final ODB response text")?;
+
+        let offset = text.find('{').context("failed to find start of ODB JSON")?;
+        let odb = serde_json::from_str(&text[offset..]).context("failed to parse final ODB")?;
+        // Local files are never cached; they could be anything (e.g. an ODB
+        // edited by hand) and would be mistaken for data handler responses.
+        if self.offline.is_none() && run_has_stopped(&odb) {
+            self.stopped_runs.insert(run_number);
+            self.store(&request, &resp);
+        }
+
+        Ok(odb)
+    }
+
+    // Request multiple plots concurrently. At most
+    // `max_concurrent_requests` plots are being prepared/downloaded at any
+    // given time. Results are returned in the same order as `args`.
+    pub fn chronobox_plots(
+        &mut self,
+        run_number: u32,
+        args: Vec<ChronoboxTimestampsArgs>,
+    ) -> Vec<Result<PathBuf>> {
+        if let Some(offline) = &self.offline {
+            return args
+                .into_iter()
+                .map(|args| {
+                    let path =
+                        offline_path(offline, &ClientRequest::ChronoboxPlot { run_number, args })?;
+                    ensure!(path.is_file(), "failed to find `{}`", path.display());
+                    Ok(path)
+                })
+                .collect();
+        }
+
+        let mut results = args
+            .iter()
+            .map(|args| {
+                self.cached(&ClientRequest::ChronoboxPlot {
+                    run_number,
+                    args: args.clone(),
+                })
+                .map(|bytes| write_plot(&bytes))
+            })
+            .collect::<Vec<_>>();
+
+        let missing = (0..args.len())
+            .filter(|&index| results[index].is_none())
+            .collect::<Vec<_>>();
+        let missing_args = missing.iter().map(|&index| args[index].clone()).collect();
+        for (index, result) in missing
+            .into_iter()
+            .zip(self.fetch_chronobox_plots(run_number, missing_args))
+        {
+            results[index] = Some(result);
+        }
+
+        results.into_iter().map(Option::unwrap).collect()
+    }
+
+    fn fetch_chronobox_plots(
+        &mut self,
+        run_number: u32,
+        args: Vec<ChronoboxTimestampsArgs>,
+    ) -> Vec<Result<PathBuf>> {
+        if args.is_empty() {
+            return Vec::new();
+        }
+        if let Err(err) = self.ensure_ready(run_number) {
+            return args.iter().map(|_| Err(clone_error(&err))).collect();
+        }
+
+        let mut results = self.chronobox_plots_once(run_number, args.to_vec());
+        // Retry all transient failures together.
+        let mut backoff = Duration::from_millis(self.config.initial_backoff_ms);
+        for _ in 0..self.config.max_retries {
+            let retry = results
+                .iter()
+                .enumerate()
+                .filter(|(_, result)| result.as_ref().is_err_and(is_transient))
+                .map(|(index, _)| index)
+                .collect::<Vec<_>>();
+            if retry.is_empty() {
+                break;
+            }
+
+            std::thread::sleep(backoff);
+            backoff *= 2;
+            let retry_args = retry.iter().map(|&index| args[index].clone()).collect();
+            for (index, result) in retry
+                .into_iter()
+                .zip(self.chronobox_plots_once(run_number, retry_args))
+            {
+                results[index] = result;
+            }
+        }
+
+        results
+            .into_iter()
+            .map(|result| result.context("failed to request chronobox plot from data handler"))
+            .collect()
+    }
+
+    fn chronobox_plots_once(
+        &mut self,
+        run_number: u32,
+        args: Vec<ChronoboxTimestampsArgs>,
+    ) -> Vec<Result<PathBuf>> {
+        let requests = args
+            .into_iter()
+            .map(|args| ClientRequest::ChronoboxPlot { run_number, args })
+            .collect::<Vec<_>>();
+        let mut results = requests.iter().map(|_| None).collect::<Vec<_>>();
+        let mut queue = requests.iter().cloned().enumerate();
+        let max_concurrent = self.config.max_concurrent_requests.max(1);
+        let timeout = Duration::from_secs(self.config.timeout);
+        let (http, config) = (self.http.clone(), self.config.clone());
+        let cache = self
+            .cache
+            .clone()
+            .filter(|_| self.stopped_runs.contains(&run_number));
+
+        std::thread::scope(|scope| {
+            let (tx, rx) = std::sync::mpsc::channel();
+            // Requests waiting for a JWT (each with its own deadline), and
+            // number of ongoing downloads.
+            let mut in_flight: Vec<(usize, String, Instant)> = Vec::new();
+            let mut downloading = 0;
+
+            loop {
+                while in_flight.len() + downloading < max_concurrent {
+                    let Some((index, request)) = queue.next() else {
+                        break;
+                    };
+                    match self.send(request) {
+                        Ok(context) => in_flight.push((index, context, Instant::now() + timeout)),
+                        Err(err) => results[index] = Some(Err(err)),
+                    }
+                }
+
+                if in_flight.is_empty() {
+                    if downloading == 0 {
+                        break;
+                    }
+                    let (index, result) = rx.recv().unwrap();
+                    results[index] = Some(result);
+                    downloading -= 1;
+                    continue;
+                }
+                while let Ok((index, result)) = rx.try_recv() {
+                    results[index] = Some(result);
+                    downloading -= 1;
+                }
+
+                let contexts = in_flight
+                    .iter()
+                    .map(|(_, context, _)| context.clone())
+                    .collect::<Vec<_>>();
+                let deadline = in_flight
+                    .iter()
+                    .map(|(_, _, deadline)| *deadline)
+                    .min()
+                    .unwrap();
+                match self.next_reply(&contexts, deadline) {
+                    Ok((context, reply)) => {
+                        let position = in_flight
+                            .iter()
+                            .position(|(_, c, _)| *c == context)
+                            .unwrap();
+                        let (index, _, _) = in_flight.swap_remove(position);
+                        match reply {
+                            Ok(jwt) => {
+                                let tx = tx.clone();
+                                let (http, config) = (&http, &config);
+                                let (cache, request) = (cache.as_ref(), &requests[index]);
+                                scope.spawn(move || {
+                                    let result = download(http, config, &jwt).and_then(|bytes| {
+                                        if let Some(cache) = cache {
+                                            cache.put(run_number, &request.cache_key(), &bytes);
+                                        }
+                                        write_plot(&bytes)
+                                    });
+                                    let _ = tx.send((index, result));
+                                });
+                                downloading += 1;
+                            }
+                            Err(err) => {
+                                results[index] = Some(Err(DataHandlerError::Internal(err).into()));
+                            }
+                        }
+                    }
+                    // Only the requests that ran out of time are given up
+                    // on. Otherwise, either the connection is gone or the data
+                    // handler stopped replying; none of the in-flight
+                    // requests will get a reply.
+                    Err(err) => {
+                        let now = Instant::now();
+                        let expired =
+                            err.chain().any(|err| {
+                                matches!(
+                                    err.downcast_ref::<DataHandlerError>(),
+                                    Some(DataHandlerError::TimedOut)
+                                )
+                            }) && in_flight.iter().any(|(_, _, deadline)| *deadline <= now);
+                        let (failed, remaining) =
+                            in_flight
+                                .drain(..)
+                                .partition::<Vec<_>, _>(|(_, _, deadline)| {
+                                    !expired || *deadline <= now
+                                });
+                        in_flight = remaining;
+                        for (index, context, _) in failed {
+                            self.abandon(&context);
+                            results[index] = Some(Err(clone_error(&err)));
+                        }
+                    }
+                }
+            }
+        });
+
+        results.into_iter().map(Option::unwrap).collect()
+    }
 }
 
-pub fn get_final_odb(run_number: u32, config: &DataHandlerConfig) -> Result<serde_json::Value> {
-    ensure!(
-        is_data_handler_ready(run_number, config).context("failed to query data handler state")?,
-        "data handler is not ready"
+// Local file that replaces the data handler's response to `request`.
+fn offline_path(offline: &OfflineConfig, request: &ClientRequest) -> Result<PathBuf> {
+    let (path, flag) = match request {
+        ClientRequest::ChronoboxPlot { .. } => (&offline.plots_dir, "--plots-dir"),
+        ClientRequest::FinalOdb { .. } => (&offline.final_odb, "--final-odb"),
+        ClientRequest::SequencerCsv { .. } => (&offline.sequencer_csv, "--sequencer-csv"),
+        ClientRequest::SpillLog { .. } => (&offline.spill_log, "--spill-log"),
+    };
+    let path = path
+        .as_ref()
+        .with_context(|| format!("no local file for `{}` (use `{flag}`)", request.label()))?;
+    let path = PathBuf::from(
+        path.to_string_lossy()
+            .replace("{run_number}", &request.run_number().to_string()),
     );
 
-    let text = ws_request(ClientRequest::FinalOdb { run_number }, config)
-        .context("failed to request final ODB from data handler")?
-        .text()
-        .context("failed to read final ODB response text")?;
+    match request {
+        ClientRequest::ChronoboxPlot { args, .. } => {
+            let time = |t: Option<f64>| t.map_or_else(String::new, |t| t.to_string());
+            Ok(path.join(format!(
+                "{}-{}-{}-{}.pdf",
+                args.board_name,
+                args.channel_number,
+                time(args.t_min),
+                time(args.t_max)
+            )))
+        }
+        _ => Ok(path),
+    }
+}
 
-    let offset = text.find('{').context("failed to find start of ODB JSON")?;
-    serde_json::from_str(&text[offset..]).context("failed to parse final ODB")
+// MIDAS run states: 1 = stopped, 2 = paused, 3 = running. Older ODBs don't
+// have a state at all, and are assumed to be stopped.
+pub fn run_has_stopped(odb: &serde_json::Value) -> bool {
+    odb.pointer("/Runinfo/State")
+        .and_then(serde_json::Value::as_u64)
+        .is_none_or(|state| state == 1)
 }
 
-#[derive(Serialize)]
-pub struct ChronoboxTimestampsArgs {
-    pub board_name: String,
-    pub channel_number: u8,
-    pub t_bins: Option<u32>,
-    pub t_max: Option<f64>,
-    pub t_min: Option<f64>,
+// Format as e.g. `1h 02m 03s` (hours and minutes are omitted if zero).
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{s}s"),
+        (0, m, s) => format!("{m}m {s:02}s"),
+        (h, m, s) => format!("{h}h {m:02}m {s:02}s"),
+    }
 }
 
-pub fn get_chronobox_plot(
-    run_number: u32,
-    args: ChronoboxTimestampsArgs,
-    config: &DataHandlerConfig,
-) -> Result<PathBuf> {
-    ensure!(
-        is_data_handler_ready(run_number, config).context("failed to query data handler state")?,
-        "data handler is not ready"
-    );
+fn download(http: &Client, config: &DataHandlerConfig, jwt: &str) -> Result<Vec<u8>> {
+    let resp = http
+        .get(format!(
+            "http://{}:{}/download/{jwt}",
+            config.host, config.port
+        ))
+        .send()
+        .map_err(DataHandlerError::from)
+        .context("failed GET request to data handler's download endpoint")?;
+    let status = resp.status();
+    if status.is_server_error() {
+        return Err(DataHandlerError::Server(status.to_string()))
+            .context("failed to download from data handler");
+    }
+    ensure!(status.is_success(), "failed to download from data handler");
+
+    Ok(resp
+        .bytes()
+        .map_err(DataHandlerError::from)
+        .context("failed to read data handler download")?
+        .to_vec())
+}
 
+fn write_plot(bytes: &[u8]) -> Result<PathBuf> {
     let mut temp = Builder::new()
         .keep(true)
         .suffix(".pdf")
         .tempfile()
         .context("failed to create temporary file")?;
-    let resp = ws_request(ClientRequest::ChronoboxPlot { run_number, args }, config)
-        .context("failed to request chronobox plot from data handler")?
-        .copy_to(&mut temp)
+    temp.write_all(bytes)
         .context("failed to write chronobox plot to temporary file")?;
 
     Ok(temp.path().to_owned())
@@ -1,29 +1,97 @@
-use crate::config::{DataHandlerConfig, EntryConfig, LogRule};
-use crate::data_handler::{get_chronobox_plot, ChronoboxTimestampsArgs, Record, SpillLog};
+use crate::config::{
+    Config, EntryConfig, LogRule, RecordTimes, ResourceSource, RuleMatching, SequencerHeaders,
+};
+use crate::data_handler::{
+    ChronoboxTimestampsArgs, DataHandlerClient, Record, SequencerRecord, SpillLog,
+};
+use crate::external_resources::{
+    assign_external_resources, find_external_resources, run_time_limits, ExternalResource,
+    RunTimeLimits,
+};
+use crate::summary::{format_run_time, format_seconds, spill_log_summary};
 use anyhow::{ensure, Context, Result};
-use std::collections::{HashMap, VecDeque};
-use std::path::PathBuf;
+use jiff::Zoned;
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+static ATTACHMENT_REFERENCE: LazyLock<regex::Regex> =
+    LazyLock::new(|| regex::Regex::new(r"elog:/(\d+)").unwrap());
+
+static CAPTURE_REFERENCE: LazyLock<regex::Regex> =
+    LazyLock::new(|| regex::Regex::new(r"\$(?:\{(\w+)\}|(\w+)|\$)").unwrap());
 
 #[derive(Debug)]
 pub struct LoggableRecord {
     pub record: Record,
     pub config: EntryConfig,
+    // Groups captured by the patterns of the matching rule(s). These can be
+    // referenced as `$name` or `${name}` in section headers (`$$` is a literal
+    // `$`).
+    pub captures: HashMap<String, String>,
+    // External resources created during (or close to) this record. See
+    // `assign_external_resources`.
+    pub resources: HashMap<ResourceSource, Vec<ExternalResource>>,
 }
 
-fn find_config<'a, T>(record: &Record, rules: &'a T) -> Option<&'a EntryConfig>
+// Numbered groups are only taken from the `event_description` pattern. Named
+// groups can come from either pattern.
+fn rule_captures(rule: &LogRule, record: &Record) -> Option<HashMap<String, String>> {
+    let sequencer_captures = rule.sequencer_name.captures(&record.sequencer_name)?;
+    let mut captures = rule.event_description.captures(&record.event_description)?;
+    for (name, value) in sequencer_captures {
+        if name.parse::<usize>().is_err() {
+            captures.entry(name).or_insert(value);
+        }
+    }
+
+    Some(captures)
+}
+
+fn find_config<'a, T: ?Sized>(
+    record: &Record,
+    rules: &'a T,
+    matching: RuleMatching,
+) -> Option<(EntryConfig, HashMap<String, String>)>
 where
     &'a T: IntoIterator<Item = &'a LogRule>,
 {
-    rules
+    let mut matches = rules
         .into_iter()
-        .find(|rule| {
-            rule.sequencer_name == record.sequencer_name
-                && rule.event_description == record.event_description
-        })
-        .map(|rule| &rule.config)
+        .filter_map(|rule| rule_captures(rule, record).map(|captures| (rule, captures)));
+
+    match matching {
+        RuleMatching::First => matches
+            .next()
+            .map(|(rule, captures)| (rule.config.clone(), captures)),
+        RuleMatching::MostSpecific => matches
+            .min_by_key(|(rule, _)| {
+                std::cmp::Reverse(
+                    rule.sequencer_name.specificity() + rule.event_description.specificity(),
+                )
+            })
+            .map(|(rule, captures)| (rule.config.clone(), captures)),
+        RuleMatching::Merge => {
+            let (first, mut captures) = matches.next()?;
+            let mut config = first.config.clone();
+            for (rule, other_captures) in matches {
+                config.merge(&rule.config);
+                for (name, value) in other_captures {
+                    captures.entry(name).or_insert(value);
+                }
+            }
+
+            Some((config, captures))
+        }
+    }
 }
 
-pub fn loggable_records<'a, T>(spill_log: &SpillLog, rules: &'a T) -> Vec<LoggableRecord>
+pub fn loggable_records<'a, T: ?Sized>(
+    spill_log: &SpillLog,
+    rules: &'a T,
+    matching: RuleMatching,
+) -> Vec<LoggableRecord>
 where
     &'a T: IntoIterator<Item = &'a LogRule>,
 {
@@ -31,14 +99,33 @@ where
         .records
         .iter()
         .filter_map(|record| {
-            find_config(record, rules).map(|config| LoggableRecord {
+            find_config(record, rules, matching).map(|(config, captures)| LoggableRecord {
                 record: record.clone(),
-                config: config.clone(),
+                config,
+                captures,
+                resources: HashMap::new(),
             })
         })
         .collect()
 }
 
+// Replace all `$name` and `${name}` in `template` with the corresponding
+// captured group, and `$$` with a literal `$`. References to groups that don't
+// exist are left as they are.
+fn expand_captures(template: &str, captures: &HashMap<String, String>) -> String {
+    CAPTURE_REFERENCE
+        .replace_all(template, |caps: &regex::Captures| {
+            let Some(name) = caps.get(1).or_else(|| caps.get(2)) else {
+                return String::from("$");
+            };
+            captures
+                .get(name.as_str())
+                .cloned()
+                .unwrap_or_else(|| caps[0].to_string())
+        })
+        .into_owned()
+}
+
 #[derive(Debug)]
 struct ChronoboxChannel {
     board_name: String,
@@ -68,6 +155,78 @@ fn find_chronobox_channel(channel_name: &str, odb: &serde_json::Value) -> Result
     Ok(found_channels.pop().unwrap())
 }
 
+// Arguments to request the plot of each channel in the Chronobox table of a
+// record (`None` if the channel can't be found in the ODB).
+fn chronobox_plot_args(
+    loggable: &LoggableRecord,
+    odb: &serde_json::Value,
+) -> Vec<Option<ChronoboxTimestampsArgs>> {
+    let Some(table_config) = &loggable.config.chronobox_table else {
+        return Vec::new();
+    };
+    if !table_config.include_attachments {
+        return Vec::new();
+    }
+
+    table_config
+        .channel_names
+        .iter()
+        .map(|channel| {
+            find_chronobox_channel(channel, odb)
+                .ok()
+                .map(|channel| ChronoboxTimestampsArgs {
+                    board_name: channel.board_name,
+                    channel_number: channel.channel_number,
+                    t_bins: None,
+                    t_max: Some(loggable.record.stop_time),
+                    t_min: Some(loggable.record.start_time),
+                })
+        })
+        .collect()
+}
+
+// e.g. `CAT - Hot Dump (11:27:13.500 to 11:27:14.250, 0.750 s long, 3.250 s
+// after previous)`, with the details in parentheses depending on `times`.
+fn record_title(
+    record: &Record,
+    previous_stop: Option<f64>,
+    times: RecordTimes,
+    run_start: &Zoned,
+) -> String {
+    let mut title = format!(
+        "{} - {}",
+        record.sequencer_name.to_uppercase(),
+        record.event_description
+    );
+
+    let mut details = Vec::new();
+    if times.wall_clock {
+        details.push(format!(
+            "{} to {}",
+            format_run_time(record.start_time, run_start, true),
+            format_run_time(record.stop_time, run_start, true)
+        ));
+    }
+    if times.duration {
+        details.push(format!(
+            "{} long",
+            format_seconds(record.stop_time - record.start_time)
+        ));
+    }
+    if let (true, Some(previous_stop)) = (times.gap, previous_stop) {
+        details.push(format!(
+            "{} after previous",
+            format_seconds(record.start_time - previous_stop)
+        ));
+    }
+    if !details.is_empty() {
+        title.push_str(&format!(" ({})", details.join(", ")));
+    }
+
+    title
+}
+
+#[derive(Default)]
 pub struct ElogEntry {
     pub text: String,
     pub attachments: Vec<PathBuf>,
@@ -81,14 +240,43 @@ impl ElogEntry {
         }
     }
 
-    pub fn add_record(
+    // Add all records of a run. Chronobox plots for the whole run are requested
+    // concurrently up front, and then slotted back in order. The final entry is
+    // exactly the same as if the plots had been requested one at a time.
+    pub fn add_records(
         &mut self,
         run_number: u32,
-        loggable: &LoggableRecord,
+        loggables: &[LoggableRecord],
         odb: &serde_json::Value,
-        handler_config: &DataHandlerConfig,
-        external_resources: &mut HashMap<PathBuf, VecDeque<PathBuf>>,
+        client: &mut DataHandlerClient,
+        times: RecordTimes,
+        run_start: &Zoned,
     ) {
+        let requests = loggables
+            .iter()
+            .flat_map(|loggable| chronobox_plot_args(loggable, odb))
+            .flatten()
+            .collect();
+        let mut plots = client.chronobox_plots(run_number, requests).into_iter();
+
+        // Gaps are relative to the previous record that is actually shown.
+        let mut previous_stop = None;
+        for loggable in loggables {
+            let title = record_title(&loggable.record, previous_stop, times, run_start);
+            if self.add_record(loggable, &title, odb, &mut plots) {
+                previous_stop = Some(loggable.record.stop_time);
+            }
+        }
+    }
+
+    // Returns whether anything was added to the entry.
+    fn add_record(
+        &mut self,
+        loggable: &LoggableRecord,
+        title: &str,
+        odb: &serde_json::Value,
+        plots: &mut impl Iterator<Item = Result<PathBuf>>,
+    ) -> bool {
         let mut sections = Vec::new();
 
         if let Some(table_config) = &loggable.config.chronobox_table {
@@ -105,16 +293,9 @@ impl ElogEntry {
                 .collect::<Vec<_>>();
 
             if table_config.include_attachments {
-                for channel in &header {
-                    if let Ok(channel) = find_chronobox_channel(channel, odb) {
-                        let args = ChronoboxTimestampsArgs {
-                            board_name: channel.board_name,
-                            channel_number: channel.channel_number,
-                            t_bins: None,
-                            t_max: Some(loggable.record.stop_time),
-                            t_min: Some(loggable.record.start_time),
-                        };
-                        if let Ok(path) = get_chronobox_plot(run_number, args, handler_config) {
+                for args in chronobox_plot_args(loggable, odb) {
+                    if args.is_some() {
+                        if let Some(Ok(path)) = plots.next() {
                             self.attachments.push(path);
                             data.push(format!("elog:/{}", self.attachments.len()));
                         } else {
@@ -124,7 +305,7 @@ impl ElogEntry {
                         data.push(String::from("<NOT_IN_ODB>"));
                     }
                 }
-                header.extend(std::iter::repeat(String::new()).take(header.len()));
+                header.extend(std::iter::repeat_n(String::new(), header.len()));
             }
 
             let mut builder = tabled::builder::Builder::new();
@@ -134,46 +315,499 @@ impl ElogEntry {
         }
 
         for config in &loggable.config.external_resources {
-            let attachment = external_resources
-                .get_mut(&config.base_path)
-                .and_then(|paths| paths.pop_front());
+            let resources = loggable
+                .resources
+                .get(&config.source)
+                .map_or(&[][..], Vec::as_slice);
+            let attachments = if resources.is_empty() {
+                vec![None]
+            } else {
+                resources
+                    .iter()
+                    .map(|resource| Some(&resource.path))
+                    .collect()
+            };
 
-            let mut text = String::new();
+            for attachment in attachments {
+                let mut text = String::new();
 
-            if let Some(header) = &config.header {
-                text.push_str(&format!("{}", header));
+                if let Some(header) = &config.header {
+                    text.push_str(&expand_captures(header, &loggable.captures));
+                }
+                if config.include_description {
+                    let description = attachment
+                        .and_then(|path| std::fs::read_to_string(path.with_extension("txt")).ok())
+                        .unwrap_or_else(|| String::from("<MISSING_DESCRIPTION> "));
+
+                    text.push_str(&description);
+                }
+                if config.include_attachment {
+                    if let Some(path) = attachment {
+                        self.attachments.push(path.clone());
+                        text.push_str(&format!("elog:/{}", self.attachments.len()));
+                    } else {
+                        text.push_str("<MISSING_ATTACHMENT>");
+                    }
+                }
+
+                if !text.is_empty() {
+                    sections.push(text);
+                }
             }
-            if config.include_description {
-                let description = attachment
-                    .clone()
-                    .and_then(|path| std::fs::read_to_string(path.with_extension("txt")).ok())
-                    .unwrap_or_else(|| String::from("<MISSING_DESCRIPTION> "));
+        }
 
-                text.push_str(&description);
+        if sections.is_empty() {
+            return false;
+        }
+        let text = format!("{title}\n{}\n\n", sections.join("\n\n"));
+        self.text.push_str(&indent::indent_by(4, text));
+
+        true
+    }
+
+    fn add_sequences(
+        &mut self,
+        sequences: &[SequencerRecord],
+        mode: SequencerHeaders,
+        tz: &jiff::tz::TimeZone,
+    ) {
+        match mode {
+            SequencerHeaders::None => {}
+            SequencerHeaders::Text => {
+                let headers = sequences
+                    .iter()
+                    .map(|sequence| sequence.header.as_str())
+                    .collect::<Vec<_>>()
+                    .join("\n\n\n");
+                match write_temp(&headers, ".txt") {
+                    Ok(path) => {
+                        self.attachments.push(path);
+                        self.text
+                            .push_str(&format!("Sequencer: elog:/{}\n", self.attachments.len()));
+                    }
+                    Err(_) => self.text.push_str("Sequencer: <MISSING_ATTACHMENT>\n"),
+                }
             }
-            if config.include_attachment {
-                if let Some(path) = attachment {
-                    self.attachments.push(path);
-                    text.push_str(&format!("elog:/{}", self.attachments.len()));
-                } else {
-                    text.push_str("<MISSING_ATTACHMENT>");
+            SequencerHeaders::Xml => {
+                self.text.push_str("Sequencer:\n");
+                for sequence in sequences {
+                    let reference = match write_temp(&sequence.xml, ".xml") {
+                        Ok(path) => {
+                            self.attachments.push(path);
+                            format!("elog:/{}", self.attachments.len())
+                        }
+                        Err(_) => String::from("<MISSING_ATTACHMENT>"),
+                    };
+                    self.text
+                        .push_str(&format!("    {}: {reference}\n", sequence.serial_number));
+                }
+            }
+            SequencerHeaders::Summary => {
+                let mut builder = tabled::builder::Builder::new();
+                builder.push_record(["Serial number", "Time", "Header"]);
+                for sequence in sequences {
+                    let time = jiff::Timestamp::from_second(sequence.midas_timestamp.into())
+                        .map_or_else(
+                            |_| sequence.midas_timestamp.to_string(),
+                            |t| t.to_zoned(tz.clone()).strftime("%F %T").to_string(),
+                        );
+                    builder.push_record([
+                        sequence.serial_number.to_string(),
+                        time,
+                        sequence.header.trim().to_string(),
+                    ]);
                 }
+                self.text.push_str(&format!(
+                    "Sequencer:\n{}\n",
+                    indent::indent_all_by(4, builder.build().to_string())
+                ));
             }
+        }
+    }
+
+    // Make sure that all `elog:/N` references in the text point to an existing
+    // attachment.
+    pub fn check_attachment_references(&self) -> Result<()> {
+        let invalid = ATTACHMENT_REFERENCE
+            .captures_iter(&self.text)
+            .filter(|caps| {
+                caps[1]
+                    .parse::<usize>()
+                    .map_or(true, |n| n == 0 || n > self.attachments.len())
+            })
+            .map(|caps| caps[0].to_string())
+            .collect::<Vec<_>>();
 
-            if !text.is_empty() {
-                sections.push(text);
+        ensure!(
+            invalid.is_empty(),
+            "references to nonexistent attachments (there are only {}): {}",
+            self.attachments.len(),
+            invalid.join(", ")
+        );
+        Ok(())
+    }
+
+    // Write the text of the entry as `entry.txt`, and copy all attachments as
+    // `<N>_<file_name>` where `N` is the same index used in the `elog:/N`
+    // references.
+    pub fn write_to_dir(&self, dir: &Path) -> Result<()> {
+        std::fs::create_dir_all(dir).context("failed to create output directory")?;
+        std::fs::write(dir.join("entry.txt"), &self.text)
+            .context("failed to write elog entry text")?;
+
+        for (i, path) in self.attachments.iter().enumerate() {
+            let file_name = path
+                .file_name()
+                .with_context(|| format!("attachment `{}` is not a file", path.display()))?;
+            let mut dest = std::ffi::OsString::from(format!("{}_", i + 1));
+            dest.push(file_name);
+
+            std::fs::copy(path, dir.join(dest))
+                .with_context(|| format!("failed to copy attachment `{}`", path.display()))?;
+        }
+
+        Ok(())
+    }
+}
+
+// Build the whole elog entry of a run (everything except the attributes).
+pub fn build_entry(
+    run_number: u32,
+    final_odb: &serde_json::Value,
+    config: &Config,
+    client: &mut DataHandlerClient,
+) -> Result<ElogEntry> {
+    client.report("Getting spill log...");
+    let spill_log = client
+        .spill_log(run_number)
+        .context("failed to get spill log from the data handler")?;
+
+    let mut records = {
+        let mut records = loggable_records(&spill_log, config.rules()?, config.rule_matching);
+        records.sort_by(|a, b| a.record.stop_time.total_cmp(&b.record.stop_time));
+
+        records
+    };
+    let RunTimeLimits {
+        start_time,
+        stop_time,
+        running,
+    } = run_time_limits(final_odb, &config.time_zone()?)
+        .context("failed to get run time limits from the final ODB")?;
+    let sources = records
+        .iter()
+        .flat_map(|loggable| loggable.config.external_resources.clone())
+        .map(|config| config.source)
+        .collect::<HashSet<_>>();
+    let mut unassigned = Vec::new();
+    for source in sources {
+        let resources = find_external_resources(&source, &start_time, &stop_time);
+        unassigned.extend(assign_external_resources(
+            &mut records,
+            &start_time,
+            &source,
+            resources,
+        ));
+    }
+    unassigned.sort_by(|a, b| a.time.cmp(&b.time));
+
+    let mut elog_entry = ElogEntry::new();
+    elog_entry.text.push_str(&format!(
+        "Run started: {} at {}\n",
+        start_time.date(),
+        start_time.time()
+    ));
+    if running {
+        // Whatever happens after this entry is created is not included.
+        elog_entry.text.push_str(&format!(
+            "Run stopped: <STILL_RUNNING> (provisional entry as of {} at {})\n",
+            stop_time.date(),
+            stop_time.time()
+        ));
+    } else {
+        elog_entry.text.push_str(&format!(
+            "Run stopped: {} at {}\n",
+            stop_time.date(),
+            stop_time.time()
+        ));
+    }
+    elog_entry.text.push('\n');
+
+    client.report("Logging header...");
+    let sequencer_headers = config.sequencer_headers()?;
+    if !matches!(sequencer_headers, SequencerHeaders::None) {
+        match client.sequencer_records(run_number) {
+            Ok(sequences) => {
+                elog_entry.add_sequences(&sequences, sequencer_headers, start_time.time_zone())
             }
+            Err(_) => elog_entry
+                .text
+                .push_str("Sequencer: <DATA_HANDLER_ERROR>\n"),
+        }
+    }
+    let times = config.record_times()?;
+    let mut summaries = Vec::new();
+    for &format in config.summary_formats()? {
+        if let Ok(path) = spill_log_summary(
+            &spill_log,
+            config.spill_log_columns()?,
+            times,
+            &start_time,
+            format,
+        ) {
+            elog_entry.attachments.push(path);
+            summaries.push(format!("elog:/{}", elog_entry.attachments.len()));
         }
+    }
+    if !summaries.is_empty() {
+        elog_entry
+            .text
+            .push_str(&format!("Spill log summary: {}\n", summaries.join(", ")));
+    }
+    elog_entry.text.push('\n');
 
-        if !sections.is_empty() {
-            let text = format!(
-                "{} - {}\n{}\n\n",
-                loggable.record.sequencer_name.to_uppercase(),
-                loggable.record.event_description,
-                sections.join("\n\n")
-            );
+    client.report("Logging records...");
+    elog_entry.add_records(run_number, &records, final_odb, client, times, &start_time);
+    if !unassigned.is_empty() {
+        elog_entry.text.push_str("Unassigned resources:\n");
+        for resource in unassigned {
+            elog_entry.text.push_str(&format!(
+                "    {} {}\n",
+                resource.time.strftime("%T%.3f"),
+                resource.path.display()
+            ));
+        }
+    }
+
+    Ok(elog_entry)
+}
+
+fn write_temp(contents: &str, suffix: &str) -> Result<PathBuf> {
+    let mut temp = tempfile::Builder::new()
+        .keep(true)
+        .suffix(suffix)
+        .tempfile()
+        .context("failed to create temporary file")?;
+    temp.write_all(contents.as_bytes())
+        .context("failed to write to temporary file")?;
+
+    Ok(temp.path().to_owned())
+}
 
-            self.text.push_str(&indent::indent_by(4, text));
+impl EntryConfig {
+    // Combine the sections of two rules. Chronobox channels and external
+    // resources (from the same source) are only included once, and
+    // attachments/descriptions are included if any of the rules asks for them.
+    fn merge(&mut self, other: &EntryConfig) {
+        match (&mut self.chronobox_table, &other.chronobox_table) {
+            (Some(table), Some(other)) => {
+                for channel in &other.channel_names {
+                    if !table.channel_names.contains(channel) {
+                        table.channel_names.push(channel.clone());
+                    }
+                }
+                table.include_attachments |= other.include_attachments;
+            }
+            (None, Some(other)) => self.chronobox_table = Some(other.clone()),
+            (_, None) => {}
+        }
+        for other in &other.external_resources {
+            match self
+                .external_resources
+                .iter_mut()
+                .find(|resource| resource.source == other.source)
+            {
+                Some(resource) => {
+                    if resource.header.is_none() {
+                        resource.header.clone_from(&other.header);
+                    }
+                    resource.include_description |= other.include_description;
+                    resource.include_attachment |= other.include_attachment;
+                }
+                None => self.external_resources.push(other.clone()),
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ExternalResourceConfig;
+
+    fn record(sequencer_name: &str, event_description: &str) -> Record {
+        Record {
+            sequencer_name: sequencer_name.to_string(),
+            event_description: event_description.to_string(),
+            start_time: 1.0,
+            stop_time: 2.0,
+            counts: HashMap::new(),
+        }
+    }
+
+    // Patterns are given as in the configuration file (e.g. `"cat"` or
+    // `{ glob = "*" }`). Each rule has a single Chronobox channel to tell them
+    // apart.
+    fn rule(sequencer_name: &str, event_description: &str, channel: &str) -> LogRule {
+        toml::from_str(&format!(
+            r#"
+sequencer_name = {sequencer_name}
+event_description = {event_description}
+config = {{ chronobox_table = {{ channel_names = ["{channel}"] }} }}
+"#
+        ))
+        .unwrap()
+    }
+
+    fn channels(config: &EntryConfig) -> Vec<String> {
+        config
+            .chronobox_table
+            .as_ref()
+            .unwrap()
+            .channel_names
+            .clone()
+    }
+
+    fn entry(text: &str, attachments: usize) -> ElogEntry {
+        ElogEntry {
+            text: text.to_string(),
+            attachments: (0..attachments)
+                .map(|i| PathBuf::from(format!("{i}.pdf")))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn valid_attachment_references() {
+        assert!(entry("plots: elog:/1 and elog:/2", 2)
+            .check_attachment_references()
+            .is_ok());
+        assert!(entry("no references", 0)
+            .check_attachment_references()
+            .is_ok());
+    }
+
+    #[test]
+    fn attachment_references_start_at_one() {
+        let err = entry("plot: elog:/0", 2)
+            .check_attachment_references()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "references to nonexistent attachments (there are only 2): elog:/0"
+        );
+    }
+
+    #[test]
+    fn attachment_references_past_the_last_attachment() {
+        let err = entry("elog:/1, elog:/3 and elog:/99999999999999999999", 2)
+            .check_attachment_references()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "references to nonexistent attachments (there are only 2): elog:/3, elog:/99999999999999999999"
+        );
+    }
+
+    #[test]
+    fn first_matching_rule() {
+        let rules = [
+            rule(r#""atm""#, r#"{ glob = "*" }"#, "A"),
+            rule(r#"{ glob = "*" }"#, r#"{ glob = "Hot *" }"#, "B"),
+            rule(r#""cat""#, r#""Hot Dump""#, "C"),
+        ];
+
+        let (config, _) =
+            find_config(&record("cat", "Hot Dump"), &rules, RuleMatching::First).unwrap();
+        assert_eq!(channels(&config), ["B"]);
+        assert!(find_config(&record("rct", "Cold Dump"), &rules, RuleMatching::First).is_none());
+    }
+
+    #[test]
+    fn exact_beats_glob_beats_regex() {
+        let mut rules = vec![
+            rule(r#"{ regex = "c.t" }"#, r#"{ regex = "Hot .*" }"#, "regex"),
+            rule(r#"{ glob = "c?t" }"#, r#"{ glob = "Hot *" }"#, "glob"),
+            rule(r#""cat""#, r#""Hot Dump""#, "exact"),
+        ];
+        let record = record("cat", "Hot Dump");
+
+        for expected in ["exact", "glob", "regex"] {
+            let (config, _) = find_config(&record, &rules, RuleMatching::MostSpecific).unwrap();
+            assert_eq!(channels(&config), [expected]);
+            rules.pop();
+        }
+    }
+
+    #[test]
+    fn most_specific_ties_go_to_first_rule() {
+        let rules = [
+            rule(r#"{ regex = "cat" }"#, r#"{ regex = ".*" }"#, "A"),
+            // Exact sequencer name, but a regex event description.
+            rule(r#""cat""#, r#"{ regex = "Hot .*" }"#, "B"),
+            // Same specificity the other way around.
+            rule(r#"{ regex = "c.t" }"#, r#""Hot Dump""#, "C"),
+        ];
+
+        let (config, _) = find_config(
+            &record("cat", "Hot Dump"),
+            &rules,
+            RuleMatching::MostSpecific,
+        )
+        .unwrap();
+        assert_eq!(channels(&config), ["B"]);
+    }
+
+    #[test]
+    fn merge_deduplicates_channels_and_resources() {
+        let mut rules = [
+            rule(r#""cat""#, r#"{ glob = "*" }"#, "SIS"),
+            rule(r#"{ glob = "*" }"#, r#""Hot Dump""#, "TPC"),
+            rule(r#"{ regex = ".*" }"#, r#"{ regex = ".*" }"#, "SIS"),
+        ];
+        let resource = |extra: &str| -> Vec<ExternalResourceConfig> {
+            toml::from_str::<EntryConfig>(&format!(
+                "external_resources = [{{ base_path = '/images'{extra} }}]"
+            ))
+            .unwrap()
+            .external_resources
+        };
+        rules[0].config.external_resources = resource(r#", header = "Dump""#);
+        rules[2].config.external_resources = resource(", include_attachment = true");
+
+        let (config, _) =
+            find_config(&record("cat", "Hot Dump"), &rules, RuleMatching::Merge).unwrap();
+        assert_eq!(channels(&config), ["SIS", "TPC"]);
+        let [resource] = &config.external_resources[..] else {
+            panic!(
+                "expected a single resource: {:?}",
+                config.external_resources
+            );
+        };
+        assert_eq!(resource.header.as_deref(), Some("Dump"));
+        assert!(resource.include_attachment);
+        assert!(!resource.include_description);
+    }
+
+    #[test]
+    fn named_regex_groups_are_captured() {
+        let rules = [rule(
+            r#"{ regex = "(?<sequencer>c.t)" }"#,
+            r#"{ regex = '(?<kind>\w+) Dump (\d+)' }"#,
+            "A",
+        )];
+
+        let (_, captures) =
+            find_config(&record("cat", "Hot Dump 7"), &rules, RuleMatching::First).unwrap();
+        assert_eq!(captures["sequencer"], "cat");
+        assert_eq!(captures["kind"], "Hot");
+        assert_eq!(captures["1"], "Hot");
+        assert_eq!(captures["2"], "7");
+        // Numbered groups only come from the event description.
+        assert_eq!(captures.len(), 4);
+        assert_eq!(
+            expand_captures("$kind dump ${2} ($sequencer)", &captures),
+            "Hot dump 7 (cat)"
+        );
+    }
+}
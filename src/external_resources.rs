@@ -1,102 +1,197 @@
+use crate::config::ResourceSource;
+use crate::data_handler::run_has_stopped;
+use crate::elog::LoggableRecord;
 use anyhow::{Context, Result};
-use jiff::{tz::TimeZone, Timestamp, Zoned};
+use jiff::civil::{Date, DateTime};
+use jiff::{tz::TimeZone, Timestamp, ToSpan, Zoned};
+use regex::Regex;
 use serde_json::Value;
-use std::collections::VecDeque;
 use std::path::PathBuf;
-use std::sync::LazyLock;
 
-// Return the (start_time, stop_time) of a run given the final JSON ODB.
-pub fn run_time_limits(odb: &Value) -> Result<(Zoned, Zoned)> {
-    let tz = TimeZone::get("Europe/Zurich").context("failed to get `Europe/Zurich` timezone")?;
+pub struct RunTimeLimits {
+    pub start_time: Zoned,
+    // Current time if the run has not stopped yet.
+    pub stop_time: Zoned,
+    pub running: bool,
+}
 
-    let start_time = odb
-        .pointer("/Runinfo/Start time binary")
+// Time of a run transition (`name` is either `Start` or `Stop`). This is the
+// `<name> time binary` Unix timestamp if available, otherwise the human
+// readable `<name> time` (in local time, i.e. `tz`).
+fn transition_time(odb: &Value, name: &str, tz: &TimeZone) -> Result<Zoned> {
+    let binary = odb
+        .pointer(&format!("/Runinfo/{name} time binary"))
         .and_then(Value::as_str)
         .and_then(|s| s.strip_prefix("0x"))
-        .context("failed to get binary start time")
-        .and_then(|s| i64::from_str_radix(s, 16).map_err(|e| anyhow::anyhow!(e)))
-        .context("failed to parse start time as i64")?;
-    let start_time =
-        Timestamp::from_second(start_time).context("failed to create start timestamp")?;
-    let start_time = Zoned::new(start_time, tz.clone());
-
-    let stop_time = odb
-        .pointer("/Runinfo/Stop time binary")
+        .and_then(|s| i64::from_str_radix(s, 16).ok())
+        .filter(|&seconds| seconds != 0)
+        .and_then(|seconds| Timestamp::from_second(seconds).ok());
+    if let Some(timestamp) = binary {
+        return Ok(Zoned::new(timestamp, tz.clone()));
+    }
+
+    // e.g. `Sun Mar 24 11:27:12 2024`
+    let text = odb
+        .pointer(&format!("/Runinfo/{name} time"))
         .and_then(Value::as_str)
-        .and_then(|s| s.strip_prefix("0x"))
-        .context("failed to get binary stop time")
-        .and_then(|s| i64::from_str_radix(s, 16).map_err(|e| anyhow::anyhow!(e)))
-        .context("failed to parse stop time as i64")?;
-    let stop_time = Timestamp::from_second(stop_time).context("failed to create stop timestamp")?;
-    let stop_time = Zoned::new(stop_time, tz);
+        .with_context(|| format!("failed to get {} time", name.to_lowercase()))?;
+    DateTime::strptime("%a %b %e %H:%M:%S %Y", text.trim())
+        .and_then(|datetime| datetime.to_zoned(tz.clone()))
+        .with_context(|| format!("failed to parse {} time `{text}`", name.to_lowercase()))
+}
+
+// Return the start and stop time of a run given the final JSON ODB.
+pub fn run_time_limits(odb: &Value, tz: &TimeZone) -> Result<RunTimeLimits> {
+    let start_time = transition_time(odb, "Start", tz)?;
+    let running = !run_has_stopped(odb);
+    let stop_time = if running {
+        // Whole seconds, same as the ODB times.
+        let now = Timestamp::from_second(Timestamp::now().as_second()).unwrap();
+        Zoned::new(now, tz.clone())
+    } else {
+        transition_time(odb, "Stop", tz)?
+    };
+
+    Ok(RunTimeLimits {
+        start_time,
+        stop_time,
+        running,
+    })
+}
+
+// Time of a file in the directory of `date`. Returns `None` if the file name
+// doesn't match the pattern.
+fn file_time(pattern: &Regex, file_name: &str, date: Date, tz: &TimeZone) -> Option<Zoned> {
+    let caps = pattern.captures(file_name)?;
+    let field = |name: &str, default: i64| match caps.name(name) {
+        Some(m) => m.as_str().parse::<i64>().ok(),
+        None => Some(default),
+    };
 
-    Ok((start_time, stop_time))
+    if let Some(timestamp) = caps.name("timestamp") {
+        let timestamp = Timestamp::from_second(timestamp.as_str().parse().ok()?).ok()?;
+        return Some(Zoned::new(timestamp, tz.clone()));
+    }
+    DateTime::new(
+        field("year", date.year().into())?.try_into().ok()?,
+        field("month", date.month().into())?.try_into().ok()?,
+        field("day", date.day().into())?.try_into().ok()?,
+        field("hour", 0)?.try_into().ok()?,
+        field("minute", 0)?.try_into().ok()?,
+        field("second", 0)?.try_into().ok()?,
+        (field("millisecond", 0)? * 1_000_000).try_into().ok()?,
+    )
+    .ok()?
+    .to_zoned(tz.clone())
+    .ok()
 }
 
-static FILE_PATTERN: LazyLock<regex::Regex> =
-    LazyLock::new(|| regex::Regex::new(r"^\d{2}\d{2}_\d{2}\.\d{3}\.png$").unwrap());
-// An external resource is created with the name
-// `base_path/<year>/<month>/<day>/hhmm_ss.mss.png`
-// Return all paths in chronological order.
+#[derive(Debug)]
+pub struct ExternalResource {
+    pub path: PathBuf,
+    pub time: Zoned,
+}
+
+// Return all the files of an external resource created between the start and
+// stop time of a run (in chronological order).
 pub fn find_external_resources(
-    base_path: PathBuf,
-    start_time: Zoned,
-    stop_time: Zoned,
-) -> VecDeque<PathBuf> {
-    let first_possible_entry = base_path
-        .join(start_time.year().to_string())
-        .join(format!("{:02}", start_time.month()))
-        .join(format!("{:02}", start_time.day()))
-        .join(format!(
-            "{:02}{:02}_{:02}.000.png",
-            start_time.hour(),
-            start_time.minute(),
-            start_time.second(),
-        ));
-    let last_possible_entry = base_path
-        .join(stop_time.year().to_string())
-        .join(format!("{:02}", stop_time.month()))
-        .join(format!("{:02}", stop_time.day()))
-        .join(format!(
-            "{:02}{:02}_{:02}.999.png",
-            stop_time.hour(),
-            stop_time.minute(),
-            stop_time.second(),
-        ));
-
-    let mut paths = VecDeque::new();
+    source: &ResourceSource,
+    start_time: &Zoned,
+    stop_time: &Zoned,
+) -> Vec<ExternalResource> {
+    let tz = start_time.time_zone();
+    let pattern = source.file_pattern.regex();
+    // Times in the ODB only have a resolution of seconds.
+    let stop_time = stop_time.checked_add(1.second()).unwrap();
 
+    // The same directory could contain the files of multiple days.
+    let mut dirs: Vec<(Date, PathBuf)> = Vec::new();
     let mut date = start_time.date();
     while date <= stop_time.date() {
-        let folder = base_path
-            .join(date.year().to_string())
-            .join(format!("{:02}", date.month()))
-            .join(format!("{:02}", date.day()));
-        if let Ok(entries) = std::fs::read_dir(&folder) {
-            let mut entries = entries
-                .filter_map(|res| res.map(|e| e.path()).ok())
-                .filter(|p| {
-                    p.is_file()
-                        && p.file_name()
-                            .and_then(|f| f.to_str())
-                            .map(|f| FILE_PATTERN.is_match(f))
-                            .unwrap_or(false)
-                })
-                .collect::<Vec<_>>();
-
-            if date == start_time.date() {
-                entries.retain(|p| p > &first_possible_entry);
-            }
-            if date == stop_time.date() {
-                entries.retain(|p| p < &last_possible_entry);
+        if let Some(dir) = source.directory.format(date) {
+            let dir = source.base_path.join(dir);
+            if !dirs.iter().any(|(_, d)| *d == dir) {
+                dirs.push((date, dir));
             }
-            entries.sort_unstable();
-
-            paths.extend(entries);
         }
 
         date = date.tomorrow().unwrap();
     }
 
-    paths
+    let mut resources = Vec::new();
+    for (date, dir) in dirs {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+
+        for path in entries.filter_map(|res| res.map(|e| e.path()).ok()) {
+            let Some(time) = path
+                .file_name()
+                .and_then(|f| f.to_str())
+                .and_then(|f| file_time(&pattern, f, date, tz))
+            else {
+                continue;
+            };
+
+            if path.is_file() && *start_time <= time && time < stop_time {
+                resources.push(ExternalResource { path, time });
+            }
+        }
+    }
+    resources.sort_by(|a, b| a.time.cmp(&b.time).then_with(|| a.path.cmp(&b.path)));
+
+    resources
+}
+
+// Assign each resource to the record (that includes resources from `source`)
+// with the closest time window. Resources further than the tolerance of the
+// source from all records are returned.
+pub fn assign_external_resources(
+    loggables: &mut [LoggableRecord],
+    run_start: &Zoned,
+    source: &ResourceSource,
+    resources: Vec<ExternalResource>,
+) -> Vec<ExternalResource> {
+    let candidates = loggables
+        .iter()
+        .enumerate()
+        .filter(|(_, loggable)| {
+            loggable
+                .config
+                .external_resources
+                .iter()
+                .any(|config| config.source == *source)
+        })
+        .map(|(index, _)| index)
+        .collect::<Vec<_>>();
+    let run_start = run_start.timestamp().as_millisecond();
+
+    let mut unassigned = Vec::new();
+    for resource in resources {
+        // Record times are in seconds since the start of the run.
+        let time = (resource.time.timestamp().as_millisecond() - run_start) as f64 / 1000.0;
+        let closest = candidates
+            .iter()
+            .map(|&index| {
+                let record = &loggables[index].record;
+                let distance = if time < record.start_time {
+                    record.start_time - time
+                } else {
+                    (time - record.stop_time).max(0.0)
+                };
+                (index, distance)
+            })
+            .min_by(|a, b| a.1.total_cmp(&b.1));
+
+        match closest {
+            Some((index, distance)) if distance <= source.tolerance as f64 => loggables[index]
+                .resources
+                .entry(source.clone())
+                .or_default()
+                .push(resource),
+            _ => unassigned.push(resource),
+        }
+    }
+
+    unassigned
 }
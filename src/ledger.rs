@@ -0,0 +1,122 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::path::PathBuf;
+
+// A successful submission of an elog entry.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct LedgerEntry {
+    pub run_number: u32,
+    pub logbook: String,
+    pub message_id: u32,
+    pub author: String,
+    pub timestamp: jiff::Timestamp,
+    // Created while the run was still going. A later (final) entry for the
+    // same run is not a duplicate.
+    #[serde(default)]
+    pub provisional: bool,
+}
+
+// Append-only CSV file with all the entries ever submitted from this computer.
+pub struct Ledger {
+    path: PathBuf,
+    entries: Vec<LedgerEntry>,
+}
+
+impl Ledger {
+    pub fn open(path: PathBuf) -> Result<Self> {
+        let entries = match std::fs::File::open(&path) {
+            Ok(file) => csv::Reader::from_reader(file)
+                .deserialize()
+                .collect::<Result<Vec<LedgerEntry>, _>>()
+                .with_context(|| format!("failed to parse `{}`", path.display()))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to read `{}`", path.display()))
+            }
+        };
+
+        Ok(Self { path, entries })
+    }
+
+    // Return the most recent final submission for the given run and logbook.
+    // Provisional entries are ignored.
+    pub fn find(&self, run_number: u32, logbook: &str) -> Option<&LedgerEntry> {
+        self.entries.iter().rev().find(|entry| {
+            entry.run_number == run_number && entry.logbook == logbook && !entry.provisional
+        })
+    }
+
+    pub fn record(&mut self, entry: LedgerEntry) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent).context("failed to create ledger directory")?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("failed to open `{}`", self.path.display()))?;
+        let is_new = file
+            .metadata()
+            .with_context(|| format!("failed to read `{}`", self.path.display()))?
+            .len()
+            == 0;
+
+        let mut writer = csv::WriterBuilder::new()
+            .has_headers(is_new)
+            .from_writer(file);
+        writer
+            .serialize(&entry)
+            .context("failed to write ledger entry")?;
+        writer.flush().context("failed to write ledger entry")?;
+
+        self.entries.push(entry);
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for LedgerEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "run {} was already logged to `{}` as message ID {} by {} at {}",
+            self.run_number,
+            self.logbook,
+            self.message_id,
+            self.author,
+            self.timestamp
+                .to_zoned(jiff::tz::TimeZone::system())
+                .strftime("%F %T"),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(run_number: u32, provisional: bool) -> LedgerEntry {
+        LedgerEntry {
+            run_number,
+            logbook: String::from("Test"),
+            message_id: 1,
+            author: String::from("Someone"),
+            timestamp: jiff::Timestamp::UNIX_EPOCH,
+            provisional,
+        }
+    }
+
+    #[test]
+    fn provisional_entries_are_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut ledger = Ledger::open(dir.path().join("ledger.csv")).unwrap();
+        ledger.record(entry(1, true)).unwrap();
+        assert!(ledger.find(1, "Test").is_none());
+
+        ledger.record(entry(1, false)).unwrap();
+        let ledger = Ledger::open(dir.path().join("ledger.csv")).unwrap();
+        assert!(ledger
+            .find(1, "Test")
+            .is_some_and(|entry| !entry.provisional));
+    }
+}
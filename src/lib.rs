@@ -0,0 +1,13 @@
+// Building and submitting ALPHA-g elog entries. The `alpha-g-elogger` binary
+// is just a command line frontend to this library.
+
+pub mod attributes;
+pub mod cache;
+pub mod config;
+pub mod data_handler;
+pub mod elog;
+pub mod external_resources;
+pub mod ledger;
+pub mod pipeline;
+pub mod submit;
+pub mod summary;
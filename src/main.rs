@@ -1,216 +1,563 @@
-use crate::config::{Config, Logbook};
-use crate::data_handler::{get_final_odb, get_sequencer_headers, get_spill_log};
-use crate::external_resources::{find_external_resources, run_time_limits};
-use crate::summary::spill_log_summary;
+use crate::prompts::{confirm_edit, edit_entry_text, prompt_attributes, prompt_reply_to};
+use alpha_g_elogger::attributes::{missing_attributes, parse_attribute, validate_attributes};
+use alpha_g_elogger::cache::{Cache, CacheMode};
+use alpha_g_elogger::config::{Config, OfflineConfig};
+use alpha_g_elogger::data_handler::DataHandlerClient;
+use alpha_g_elogger::ledger::Ledger;
+use alpha_g_elogger::pipeline::prepare_entry;
 use anyhow::{ensure, Context, Result};
-use clap::Parser;
-use dialoguer::{theme::ColorfulTheme, Input, Select};
-use elog::{loggable_records, ElogEntry};
-use indicatif::{ProgressBar, ProgressStyle};
-use std::collections::{HashMap, HashSet};
-use std::ffi::OsStr;
-use std::io::Write;
-use std::path::PathBuf;
-use std::process::Command;
-use tempfile::NamedTempFile;
-
-mod config;
-mod data_handler;
-mod elog;
-mod external_resources;
-mod summary;
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use indicatif::{ProgressBar, ProgressFinish, ProgressStyle};
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+
+mod prompts;
+mod watch;
 
 #[derive(Parser)]
-#[command(version)]
-/// Create an elog for a single run
+#[command(
+    version,
+    subcommand_negates_reqs = true,
+    override_usage = "alpha-g-elogger [OPTIONS] <RUNS>...\n       alpha-g-elogger [OPTIONS] <COMMAND>"
+)]
+/// Create an elog for one or more runs
 struct Args {
-    /// ALPHA-g run number
-    run_number: u32,
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// ALPHA-g run numbers (e.g. `12001`, `12001-12015` or `12001,12004,12010`)
+    #[arg(required = true)]
+    runs: Vec<RunSelection>,
     /// Path to a configuration file (overrides the default configuration)
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     config_file: Option<PathBuf>,
+    /// Logbook to submit the elog entries to (overrides the default logbook)
+    #[arg(short, long, global = true)]
+    logbook: Option<String>,
+    /// Do not use the local cache of data handler responses
+    #[arg(long, global = true)]
+    no_cache: bool,
+    /// Request everything from the data handler again (and update the cache)
+    #[arg(long, global = true, conflicts_with = "no_cache")]
+    refresh: bool,
+    /// Value of an attribute of the elog entry (can be used multiple times)
+    #[arg(
+        short,
+        long = "attribute",
+        value_name = "NAME=VALUE",
+        value_parser = parse_attribute
+    )]
+    attributes: Vec<(String, String)>,
+    /// Shorthand for `--attribute Author=<AUTHOR>`
+    #[arg(long)]
+    author: Option<String>,
+    /// Shorthand for `--attribute Type=<TYPE>`
+    #[arg(long = "type", value_name = "TYPE")]
+    entry_type: Option<String>,
+    /// Shorthand for `--attribute Subject=<SUBJECT>`
+    #[arg(long)]
+    subject: Option<String>,
+    /// Message ID of the parent entry to reply to
+    #[arg(long, value_name = "MESSAGE_ID")]
+    reply_to: Option<u32>,
+    /// Create a new thread instead of replying to an existing entry
+    #[arg(long, conflicts_with = "reply_to")]
+    new_thread: bool,
+    /// Build and print the elog entry without submitting it
+    #[arg(long)]
+    dry_run: bool,
+    /// Copy the text and attachments of the elog entry to this directory (one
+    /// sub-directory per run if multiple runs are logged)
+    #[arg(long, requires = "dry_run")]
+    output_dir: Option<PathBuf>,
+    /// Submit the elog entry even if the run has already been logged
+    #[arg(long)]
+    force: bool,
+    /// Open the text of the elog entry in a text editor before submitting it
+    #[arg(long)]
+    edit: bool,
+    /// Do not ask whether to edit the text of the elog entry (this is only
+    /// asked if some other value had to be asked for too)
+    #[arg(long, conflicts_with = "edit")]
+    no_edit: bool,
+    /// Wait for the data handler to finish processing the run instead of
+    /// failing if it is not ready yet
+    #[arg(long)]
+    wait: bool,
+    /// Build the elog entries from local files instead of the data handler
+    /// (see the `offline` section of the configuration, which can also enable
+    /// this by default)
+    #[arg(long)]
+    offline: bool,
+    /// Final ODB JSON file to use instead of the data handler (implies
+    /// `--offline`)
+    #[arg(long, value_name = "PATH")]
+    final_odb: Option<PathBuf>,
+    /// Spill log CSV file to use instead of the data handler (implies
+    /// `--offline`)
+    #[arg(long, value_name = "PATH")]
+    spill_log: Option<PathBuf>,
+    /// Sequencer CSV file to use instead of the data handler (implies
+    /// `--offline`)
+    #[arg(long, value_name = "PATH")]
+    sequencer_csv: Option<PathBuf>,
+    /// Directory with the Chronobox plots to use instead of the data handler
+    /// (implies `--offline`)
+    #[arg(long, value_name = "DIR")]
+    plots_dir: Option<PathBuf>,
 }
 
-fn main() -> Result<()> {
-    let args = Args::parse();
-
-    let config = args.config_file.unwrap_or_else(|| {
-        directories::ProjectDirs::from("com", "ALPHA", "ALPHA-g-Elogger")
-            .unwrap()
-            .config_local_dir()
-            .join("Elogger.toml")
-    });
-    let config = std::fs::read_to_string(&config)
-        .with_context(|| format!("failed to read `{}`", config.display()))?;
-    let config: Config = toml::from_str(&config).context("failed to parse configuration")?;
+#[derive(Subcommand)]
+enum Command {
+    /// Automatically create an elog for each run as soon as it ends
+    Watch(WatchArgs),
+}
 
-    let final_odb = get_final_odb(args.run_number, &config.data_handler)
-        .context("failed to get the final ODB from the data handler")?;
+#[derive(clap::Args)]
+struct WatchArgs {
+    /// First run to log [default: the run after the last one logged by a
+    /// previous `watch`]
+    #[arg(long)]
+    from: Option<u32>,
+    /// Seconds to wait between polls of the data handler
+    #[arg(long, default_value_t = 30)]
+    poll_interval: u64,
+    /// Seconds to keep waiting for a run that is not ready after the next run
+    /// has already ended (the run is then assumed to not exist)
+    #[arg(long, default_value_t = 3600)]
+    skip_missing_after: u64,
+}
 
-    let parent_id: String = Input::with_theme(&ColorfulTheme::default())
-        .with_prompt("Parent message ID (leave empty to create a new thread instead)")
-        .allow_empty(true)
-        .validate_with(|input: &String| -> Result<(), &str> {
-            if input.is_empty() {
-                Ok(())
-            } else {
-                input
+// Anything bigger is most likely a typo (e.g. `1-3000000000`).
+const MAX_RUN_RANGE: u32 = 10_000;
+
+#[derive(Clone, Debug)]
+struct RunSelection(Vec<u32>);
+
+impl std::str::FromStr for RunSelection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut runs = Vec::new();
+        for part in s.split(',') {
+            let parse = |s: &str| {
+                s.trim()
                     .parse::<u32>()
-                    .map(|_| ())
-                    .map_err(|_| "message ID must be a non-negative integer")
+                    .map_err(|_| format!("`{s}` is not a valid run number"))
+            };
+
+            if let Some((first, last)) = part.split_once('-') {
+                let (first, last) = (parse(first)?, parse(last)?);
+                if first > last {
+                    return Err(format!("`{part}` is not a valid run range"));
+                }
+                if last - first >= MAX_RUN_RANGE {
+                    return Err(format!(
+                        "`{part}` has more than {MAX_RUN_RANGE} runs (split it into smaller ranges)"
+                    ));
+                }
+                runs.extend(first..=last);
+            } else {
+                runs.push(parse(part)?);
             }
-        })
-        .interact_text()
-        .context("failed to read parent message ID")?;
-
-    let mut attributes = Vec::new();
-    let author: String = Input::with_theme(&ColorfulTheme::default())
-        .with_prompt("Author")
-        .interact_text()
-        .context("failed to read author")?;
-    attributes.push(format!("Author={author}"));
-    if let Logbook::DataLog = config.elog.logbook {
-        let types = &[
-            "Baseline Log",
-            "Pbar Log",
-            "Trapping Series",
-            "Electron Log",
-            "Positron Log",
+        }
+
+        Ok(Self(runs))
+    }
+}
+
+impl Args {
+    // Local files to use instead of the data handler (if any). Command line
+    // paths take precedence over the configuration file.
+    fn offline_config(&self, config: Option<OfflineConfig>) -> Option<OfflineConfig> {
+        let files = [
+            &self.final_odb,
+            &self.spill_log,
+            &self.sequencer_csv,
+            &self.plots_dir,
         ];
-        let selection = Select::with_theme(&ColorfulTheme::default())
-            .with_prompt("Type")
-            .default(0)
-            .items(&types[..])
-            .interact()
-            .context("failed to read logbook")?;
-        attributes.push(format!("Type={}", types[selection]));
-        attributes.push(format!("Run={}", args.run_number));
-        attributes.push(format!(
-            "Subject={}",
-            final_odb
-                .pointer("/Experiment/Edit on start/Comment")
-                .and_then(serde_json::Value::as_str)
-                .map(|s| if s.is_empty() {
-                    "MISSING START-RUN COMMENT"
-                } else {
-                    s
-                })
-                .context("failed to get comment from ODB")?
-        ));
+        let enabled = config.as_ref().is_some_and(|config| config.enabled);
+        if !enabled && !self.offline && files.iter().all(|file| file.is_none()) {
+            return None;
+        }
+
+        let mut config = config.unwrap_or_default();
+        for (field, value) in [
+            (&mut config.final_odb, &self.final_odb),
+            (&mut config.spill_log, &self.spill_log),
+            (&mut config.sequencer_csv, &self.sequencer_csv),
+            (&mut config.plots_dir, &self.plots_dir),
+        ] {
+            if value.is_some() {
+                field.clone_from(value);
+            }
+        }
+
+        Some(config)
     }
 
-    let spinner = ProgressBar::new_spinner()
-        .with_style(ProgressStyle::default_spinner().tick_chars("⠁⠂⠄⡀⢀⠠⠐⠈ "));
-    spinner.enable_steady_tick(std::time::Duration::from_millis(100));
+    fn cache_mode(&self) -> CacheMode {
+        if self.no_cache {
+            CacheMode::Disabled
+        } else if self.refresh {
+            CacheMode::Refresh
+        } else {
+            CacheMode::Enabled
+        }
+    }
+
+    // All attribute values provided in the command line.
+    fn provided_attributes(&self) -> Vec<(String, String)> {
+        let mut attributes = self.attributes.clone();
+        for (name, value) in [
+            ("Author", &self.author),
+            ("Type", &self.entry_type),
+            ("Subject", &self.subject),
+        ] {
+            if let Some(value) = value {
+                attributes.push((name.to_string(), value.clone()));
+            }
+        }
 
-    spinner.set_message("Getting spill log...");
-    let spill_log = get_spill_log(args.run_number, &config.data_handler)
-        .context("failed to get spill log from the data handler")?;
+        attributes
+    }
+}
 
-    let records = {
-        let mut records = loggable_records(&spill_log, &config.rules);
-        records.sort_by(|a, b| a.record.stop_time.partial_cmp(&b.record.stop_time).unwrap());
+// Global options can be used before or after a subcommand (e.g. `-c cfg.toml
+// watch`), but options that only make sense when logging runs can't be used
+// with a subcommand at all. Clap's `args_conflicts_with_subcommands` would
+// reject both.
+fn parse_args<I, T>(itr: I) -> Result<Args, clap::Error>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    let mut command = Args::command();
+    let matches = command.try_get_matches_from_mut(itr)?;
+    if let Some((subcommand, _)) = matches.subcommand() {
+        let conflict = command
+            .get_arguments()
+            .find(|arg| {
+                !arg.is_global_set()
+                    && matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine)
+            })
+            .map(|arg| {
+                arg.get_long()
+                    .map_or_else(|| arg.get_id().to_string(), |long| format!("--{long}"))
+            });
+        if let Some(name) = conflict {
+            return Err(command.error(
+                ErrorKind::ArgumentConflict,
+                format!("`{name}` can't be used with `{subcommand}`"),
+            ));
+        }
+    }
 
-        records
-    };
-    let (start_time, stop_time) =
-        run_time_limits(&final_odb).context("failed to get run time limits from the final ODB")?;
-    let base_paths = records
-        .iter()
-        .flat_map(|loggable| loggable.config.external_resources.clone())
-        .map(|config| config.base_path)
-        .collect::<HashSet<_>>();
-    let mut external_resources = base_paths
-        .into_iter()
-        .map(|base_path| {
-            (
-                base_path.clone(),
-                find_external_resources(base_path, start_time.clone(), stop_time.clone()),
-            )
-        })
-        .collect::<HashMap<_, _>>();
-
-    let mut elog_entry = ElogEntry::new();
-    elog_entry.text.push_str(&format!(
-        "Run started: {} at {}\n",
-        start_time.date(),
-        start_time.time()
-    ));
-    elog_entry.text.push_str(&format!(
-        "Run stopped: {} at {}\n",
-        stop_time.date(),
-        stop_time.time()
-    ));
-    elog_entry.text.push_str("\n");
-
-    spinner.set_message("Logging header...");
-    /*
-    if let Ok(path) = get_sequencer_headers(args.run_number, &config.data_handler) {
-        elog_entry.attachments.push(path);
-        elog_entry.text.push_str(&format!(
-            "Sequencer: elog:/{}\n",
-            elog_entry.attachments.len()
-        ));
+    Args::from_arg_matches(&matches)
+}
+
+fn main() -> Result<()> {
+    let args = parse_args(std::env::args_os()).unwrap_or_else(|err| err.exit());
+
+    let config = args
+        .config_file
+        .clone()
+        .unwrap_or_else(|| project_dirs().config_local_dir().join("Elogger.toml"));
+    let config = std::fs::read_to_string(&config)
+        .with_context(|| format!("failed to read `{}`", config.display()))?;
+    let mut config: Config = toml::from_str(&config).context("failed to parse configuration")?;
+    if let Some(logbook) = args.logbook.clone() {
+        config.elog.logbook = logbook;
     }
-    */
-    if let Ok(path) = spill_log_summary(&spill_log, &config.spill_log_columns) {
-        elog_entry.attachments.push(path);
-        elog_entry.text.push_str(&format!(
-            "Spill log summary: elog:/{}\n",
-            elog_entry.attachments.len()
-        ));
+    config.offline = args.offline_config(config.offline.take());
+    let profile = config.profile()?;
+
+    if let Some(Command::Watch(watch_args)) = &args.command {
+        return watch::watch(&config, watch_args, args.cache_mode());
     }
-    elog_entry.text.push_str("\n");
 
-    spinner.set_message("Logging records...");
-    for loggable in records {
-        elog_entry.add_record(
-            args.run_number,
-            &loggable,
-            &final_odb,
-            &config.data_handler,
-            &mut external_resources,
-        );
+    let mut attributes = args.provided_attributes();
+    validate_attributes(&profile.attributes, &attributes)?;
+
+    let mut missing_flags = missing_attributes(&profile.attributes, &attributes)
+        .into_iter()
+        .map(|name| format!("--attribute {name}=<VALUE>"))
+        .collect::<Vec<_>>();
+    if args.reply_to.is_none() && !args.new_thread {
+        missing_flags.insert(0, String::from("--reply-to <MESSAGE_ID> (or --new-thread)"));
     }
+    ensure!(
+        missing_flags.is_empty() || std::io::stdin().is_terminal(),
+        "stdin is not a terminal and the following values were not provided: {}",
+        missing_flags.join(", ")
+    );
 
-    let mut temp_text =
-        NamedTempFile::new().context("failed to create temporary elog text file")?;
-    temp_text
-        .write_all(elog_entry.text.as_bytes())
-        .context("failed to write to temporary elog text file")?;
+    let (reply_to, mut prompted) = match args.reply_to {
+        Some(id) => (Some(id), false),
+        None if args.new_thread => (None, false),
+        None => (prompt_reply_to()?, true),
+    };
+    prompted |= prompt_attributes(&profile.attributes, &mut attributes)?;
+    let options = EntryOptions {
+        reply_to,
+        attributes,
+        dry_run: args.dry_run,
+        force: args.force,
+        wait: args.wait,
+        cache: args.cache_mode(),
+        // Someone who provided every value as a flag is most likely not
+        // around to answer any more questions.
+        edit: match (args.edit, args.no_edit) {
+            (true, _) => Some(true),
+            (_, true) => Some(false),
+            _ if prompted => None,
+            _ => Some(false),
+        },
+    };
+    let mut ledger = Ledger::open(ledger_file()).context("failed to open submission ledger")?;
 
-    spinner.set_message("Pushing to server...");
-    let mut cmd = Command::new(&config.elog.client);
-    cmd.args(["-h", &config.elog.host])
-        .args(["-p", &config.elog.port.to_string()])
-        .args(["-l", &config.elog.logbook.to_string()])
-        .args(
-            elog_entry
-                .attachments
-                .iter()
-                .flat_map(|path| [OsStr::new("-f"), path.as_ref()]),
+    let mut seen = HashSet::new();
+    let run_numbers = args
+        .runs
+        .into_iter()
+        .flat_map(|selection| selection.0)
+        .filter(|&run_number| seen.insert(run_number))
+        .collect::<Vec<_>>();
+
+    if let [run_number] = run_numbers[..] {
+        return log_run(
+            run_number,
+            &config,
+            &options,
+            &mut ledger,
+            args.output_dir.as_deref(),
         )
-        .args(attributes.iter().flat_map(|attribute| ["-a", attribute]))
-        .arg("-x")
-        .args(["-n", "1"])
-        .args([OsStr::new("-m"), temp_text.path().as_ref()]);
-    if !parent_id.is_empty() {
-        cmd.args(["-r", &parent_id]);
+        .map(|_| ());
+    }
+
+    let mut failures = 0;
+    let mut outcomes = Vec::new();
+    for &run_number in &run_numbers {
+        if options.dry_run {
+            println!("===== Run {run_number} =====");
+        }
+        let output_dir = args
+            .output_dir
+            .as_ref()
+            .map(|dir| dir.join(run_number.to_string()));
+
+        let outcome = log_run(
+            run_number,
+            &config,
+            &options,
+            &mut ledger,
+            output_dir.as_deref(),
+        );
+        if outcome.is_err() {
+            failures += 1;
+        }
+        outcomes.push((run_number, outcome));
     }
 
-    let output = cmd.output().context("failed to run the elog client")?;
+    println!("\nSummary:");
+    for (run_number, outcome) in outcomes {
+        match outcome {
+            Ok(Some(message_id)) => println!("  Run {run_number}: created message ID {message_id}"),
+            Ok(None) => println!("  Run {run_number}: OK (dry run)"),
+            Err(err) => println!("  Run {run_number}: FAILED ({err:#})"),
+        }
+    }
     ensure!(
-        output.status.success(),
-        "elog client failed with {}",
-        output.status
+        failures == 0,
+        "failed to log {failures} out of {} runs",
+        run_numbers.len()
     );
-    spinner.finish_and_clear();
-    // The elog client doesn't report errors correctly. With some failure modes,
-    // it will still return a successful exit code but print an error message to
-    // stdout or stderr. Basically, there is no way to know if the elog was
-    // successfully created other than reading all output of the command.
-    let _ = std::io::stdout().write_all(&output.stdout);
-    let _ = std::io::stderr().write_all(&output.stderr);
 
     Ok(())
 }
+
+fn project_dirs() -> directories::ProjectDirs {
+    directories::ProjectDirs::from("com", "ALPHA", "ALPHA-g-Elogger").unwrap()
+}
+
+// Both directories can be overridden with an environment variable (e.g. to
+// keep tests away from the real cache and ledger).
+fn cache_dir() -> PathBuf {
+    std::env::var_os("ALPHA_G_ELOGGER_CACHE_DIR")
+        .map_or_else(|| project_dirs().cache_dir().to_path_buf(), PathBuf::from)
+}
+
+fn data_dir() -> PathBuf {
+    std::env::var_os("ALPHA_G_ELOGGER_DATA_DIR").map_or_else(
+        || project_dirs().data_local_dir().to_path_buf(),
+        PathBuf::from,
+    )
+}
+
+fn ledger_file() -> PathBuf {
+    data_dir().join("ledger.csv")
+}
+
+fn data_handler_client(config: &Config, cache: CacheMode) -> Result<DataHandlerClient> {
+    let mut client = DataHandlerClient::new(config.data_handler.clone())?;
+    let dir = cache_dir().join("data_handler");
+    if let Some(cache) = Cache::open(dir, cache, &config.cache) {
+        client.set_cache(cache);
+    }
+    if let Some(offline) = &config.offline {
+        client.set_offline(offline.clone());
+    }
+
+    Ok(client)
+}
+
+// Values shared by the elog entries of all runs.
+struct EntryOptions {
+    reply_to: Option<u32>,
+    // Attribute values provided by the user.
+    attributes: Vec<(String, String)>,
+    dry_run: bool,
+    force: bool,
+    // Whether to edit the text before submitting (ask the user if `None`).
+    edit: Option<bool>,
+    // Whether to wait for the data handler to be ready for the run.
+    wait: bool,
+    cache: CacheMode,
+}
+
+// Create the elog entry for a single run. Return the message ID of the new
+// entry (or `None` in dry run mode).
+fn log_run(
+    run_number: u32,
+    config: &Config,
+    options: &EntryOptions,
+    ledger: &mut Ledger,
+    output_dir: Option<&Path>,
+) -> Result<Option<u32>> {
+    if let Some(previous) = ledger.find(run_number, &config.elog.logbook) {
+        if options.dry_run {
+            eprintln!("Warning: {previous}");
+        } else {
+            ensure!(options.force, "{previous} (use `--force` to submit anyway)");
+        }
+    }
+
+    let spinner = ProgressBar::new_spinner()
+        .with_style(ProgressStyle::default_spinner().tick_chars("⠁⠂⠄⡀⢀⠠⠐⠈ "))
+        .with_finish(ProgressFinish::AndClear);
+    spinner.enable_steady_tick(std::time::Duration::from_millis(100));
+
+    let mut client = data_handler_client(config, options.cache)?;
+    client.set_progress_bar(spinner.clone());
+    if options.wait {
+        client.wait_until_ready(run_number)?;
+    }
+    let mut prepared = prepare_entry(run_number, &options.attributes, config, &mut client)?;
+
+    let interactive = std::io::stdin().is_terminal();
+    let edit = match options.edit {
+        Some(edit) => edit,
+        None if interactive => spinner.suspend(confirm_edit)?,
+        None => false,
+    };
+    if edit {
+        spinner.suspend(|| edit_entry_text(&mut prepared.entry, interactive))?;
+    }
+
+    if options.dry_run {
+        spinner.finish_and_clear();
+        println!("Logbook: {}", config.elog.logbook);
+        match options.reply_to {
+            Some(id) => println!("Reply to: {id}"),
+            None => println!("Reply to: <NEW_THREAD>"),
+        }
+        for (name, value) in &prepared.attributes {
+            println!("{name}: {value}");
+        }
+        println!("\n{}", prepared.entry.text);
+        for (i, path) in prepared.entry.attachments.iter().enumerate() {
+            println!("elog:/{} -> {}", i + 1, path.display());
+        }
+
+        if let Some(dir) = output_dir {
+            prepared
+                .entry
+                .write_to_dir(dir)
+                .with_context(|| format!("failed to write elog entry to `{}`", dir.display()))?;
+        }
+
+        return Ok(None);
+    }
+
+    spinner.set_message("Pushing to server...");
+    let submission = prepared.submit(options.reply_to, config, ledger)?;
+    spinner.finish_and_clear();
+    println!(
+        "Created elog entry for run {run_number} with message ID {}",
+        submission.message_id
+    );
+    if let Some(err) = submission.ledger_error {
+        eprintln!("Warning: failed to record submission in ledger: {err:#}");
+    }
+
+    Ok(Some(submission.message_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn global_options_before_subcommand() {
+        for args in [
+            &["alpha-g-elogger", "-c", "cfg.toml", "watch"][..],
+            &["alpha-g-elogger", "--no-cache", "watch"],
+            &["alpha-g-elogger", "-l", "X", "watch"],
+            &["alpha-g-elogger", "-c", "cfg.toml", "watch", "--from", "5"],
+            &["alpha-g-elogger", "watch", "-c", "cfg.toml"],
+        ] {
+            let parsed = Args::try_parse_from(args).unwrap();
+            assert!(
+                matches!(parsed.command, Some(Command::Watch(_))),
+                "{args:?}"
+            );
+            assert!(parsed.runs.is_empty());
+            assert!(parse_args(args).is_ok(), "{args:?}");
+        }
+
+        let parsed =
+            parse_args(["alpha-g-elogger", "-c", "cfg.toml", "watch", "--from", "5"]).unwrap();
+        assert!(matches!(
+            parsed.command,
+            Some(Command::Watch(WatchArgs { from: Some(5), .. }))
+        ));
+        assert_eq!(parsed.config_file, Some(PathBuf::from("cfg.toml")));
+    }
+
+    #[test]
+    fn runs_are_required_without_subcommand() {
+        let parsed =
+            parse_args(["alpha-g-elogger", "-c", "cfg.toml", "12001-12003,12001"]).unwrap();
+        assert!(parsed.command.is_none());
+        assert_eq!(parsed.runs[0].0, [12001, 12002, 12003, 12001]);
+
+        let err = parse_args(["alpha-g-elogger", "-c", "cfg.toml"])
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::MissingRequiredArgument);
+    }
+
+    #[test]
+    fn run_options_conflict_with_subcommand() {
+        for args in [
+            &["alpha-g-elogger", "--dry-run", "watch"][..],
+            &["alpha-g-elogger", "--author", "Someone", "watch"],
+        ] {
+            let err = parse_args(args).err().unwrap();
+            assert_eq!(err.kind(), ErrorKind::ArgumentConflict, "{args:?}");
+        }
+    }
+}
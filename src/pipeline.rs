@@ -0,0 +1,95 @@
+// The whole process of logging a single run, without any user interaction:
+// get the final ODB, resolve the attributes, build the entry, submit it, and
+// record the submission in the ledger. Anything interactive (e.g. prompting for
+// missing attributes, or editing the text) happens in between
+// `prepare_entry` and `PreparedEntry::submit`.
+
+use crate::attributes::resolve_attributes;
+use crate::config::Config;
+use crate::data_handler::{run_has_stopped, DataHandlerClient};
+use crate::elog::{build_entry, ElogEntry};
+use crate::ledger::{Ledger, LedgerEntry};
+use crate::submit::submit_entry;
+use anyhow::{Context, Result};
+
+// Elog entry of a run, ready to be submitted.
+pub struct PreparedEntry {
+    pub run_number: u32,
+    pub final_odb: serde_json::Value,
+    pub attributes: Vec<(String, String)>,
+    pub entry: ElogEntry,
+}
+
+// A successful submission.
+pub struct Submission {
+    pub message_id: u32,
+    // The entry already exists even if it couldn't be recorded in the ledger.
+    // Failing would only make callers believe that the submission failed (and
+    // retry it).
+    pub ledger_error: Option<anyhow::Error>,
+}
+
+// `provided` are the attribute values that can't be derived from the run (see
+// `attributes::missing_attributes`).
+pub fn prepare_entry(
+    run_number: u32,
+    provided: &[(String, String)],
+    config: &Config,
+    client: &mut DataHandlerClient,
+) -> Result<PreparedEntry> {
+    client.report("Getting final ODB...");
+    let final_odb = client
+        .final_odb(run_number)
+        .context("failed to get the final ODB from the data handler")?;
+
+    let attributes = resolve_attributes(
+        &config.profile()?.attributes,
+        provided,
+        run_number,
+        &final_odb,
+    )
+    .context("failed to resolve elog entry attributes")?;
+
+    let entry = build_entry(run_number, &final_odb, config, client)?;
+
+    Ok(PreparedEntry {
+        run_number,
+        final_odb,
+        attributes,
+        entry,
+    })
+}
+
+impl PreparedEntry {
+    // Submit the entry to the logbook in `config`, and record it in `ledger`.
+    pub fn submit(
+        &self,
+        reply_to: Option<u32>,
+        config: &Config,
+        ledger: &mut Ledger,
+    ) -> Result<Submission> {
+        let message_id = submit_entry(&self.entry, &self.attributes, reply_to, &config.elog)
+            .context("failed to submit elog entry")?;
+
+        let ledger_error = ledger
+            .record(LedgerEntry {
+                run_number: self.run_number,
+                logbook: config.elog.logbook.clone(),
+                message_id,
+                author: self
+                    .attributes
+                    .iter()
+                    .find(|(name, _)| name == "Author")
+                    .map(|(_, value)| value.clone())
+                    .unwrap_or_default(),
+                timestamp: jiff::Timestamp::now(),
+                provisional: !run_has_stopped(&self.final_odb),
+            })
+            .err();
+
+        Ok(Submission {
+            message_id,
+            ledger_error,
+        })
+    }
+}
@@ -0,0 +1,108 @@
+use alpha_g_elogger::config::{AttributeConfig, AttributeSource};
+use alpha_g_elogger::elog::ElogEntry;
+use anyhow::{Context, Result};
+use dialoguer::{theme::ColorfulTheme, Confirm, Editor, Input, Select};
+
+// `None` to create a new thread instead.
+pub fn prompt_reply_to() -> Result<Option<u32>> {
+    let parent_id: String = Input::with_theme(&ColorfulTheme::default())
+        .with_prompt("Parent message ID (leave empty to create a new thread instead)")
+        .allow_empty(true)
+        .validate_with(|input: &String| -> Result<(), &str> {
+            if input.is_empty() {
+                Ok(())
+            } else {
+                input
+                    .parse::<u32>()
+                    .map(|_| ())
+                    .map_err(|_| "message ID must be a non-negative integer")
+            }
+        })
+        .interact_text()
+        .context("failed to read parent message ID")?;
+
+    if parent_id.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(
+            parent_id
+                .parse()
+                .context("failed to parse parent message ID")?,
+        ))
+    }
+}
+
+// Ask the user for all the attributes that need input and were not provided.
+// Returns whether anything was asked at all.
+pub fn prompt_attributes(
+    configs: &[AttributeConfig],
+    provided: &mut Vec<(String, String)>,
+) -> Result<bool> {
+    let mut prompted = false;
+    for config in configs {
+        if provided.iter().any(|(name, _)| name == &config.name) {
+            continue;
+        }
+
+        let value = match &config.source {
+            AttributeSource::Prompt => Input::with_theme(&ColorfulTheme::default())
+                .with_prompt(&config.name)
+                .interact_text()
+                .with_context(|| format!("failed to read `{}`", config.name))?,
+            AttributeSource::Select { options } => {
+                let selection = Select::with_theme(&ColorfulTheme::default())
+                    .with_prompt(&config.name)
+                    .default(0)
+                    .items(options)
+                    .interact()
+                    .with_context(|| format!("failed to read `{}`", config.name))?;
+
+                options[selection].clone()
+            }
+            AttributeSource::Odb { .. } | AttributeSource::RunNumber => continue,
+        };
+        provided.push((config.name.clone(), value));
+        prompted = true;
+    }
+
+    Ok(prompted)
+}
+
+pub fn confirm_edit() -> Result<bool> {
+    Confirm::with_theme(&ColorfulTheme::default())
+        .with_prompt("Edit the elog entry text before submitting?")
+        .default(false)
+        .interact()
+        .context("failed to read confirmation")
+}
+
+// Let the user edit the text of an entry in their `$VISUAL`/`$EDITOR`. The
+// edited text can only reference attachments that actually exist.
+pub fn edit_entry_text(entry: &mut ElogEntry, interactive: bool) -> Result<()> {
+    loop {
+        if let Some(text) = Editor::new()
+            .extension(".txt")
+            .trim_newlines(false)
+            .edit(&entry.text)
+            .context("failed to edit elog entry text")?
+        {
+            entry.text = text;
+        }
+
+        let Err(err) = entry.check_attachment_references() else {
+            return Ok(());
+        };
+        if !interactive {
+            return Err(err);
+        }
+        eprintln!("Error: {err:#}");
+        let retry = Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt("Edit the elog entry text again?")
+            .default(true)
+            .interact()
+            .context("failed to read confirmation")?;
+        if !retry {
+            return Err(err);
+        }
+    }
+}
@@ -0,0 +1,246 @@
+use crate::config::ElogConfig;
+use crate::elog::ElogEntry;
+use reqwest::blocking::multipart::{Form, Part};
+use reqwest::header::LOCATION;
+use reqwest::redirect::Policy;
+use reqwest::StatusCode;
+
+#[derive(Debug)]
+pub enum SubmitError {
+    // Failed to read an attachment from disk.
+    Attachment(std::path::PathBuf, std::io::Error),
+    // Failed to talk to the ELOG server (connection refused, etc.). The entry
+    // was definitely not created.
+    Request(reqwest::Error),
+    // The entry was sent, but it is unknown whether the ELOG server created it
+    // (e.g. no response in time, or an unexpected redirect).
+    Unconfirmed(String),
+    // The ELOG server answered, but it did not accept the entry.
+    Rejected(String),
+}
+
+impl std::fmt::Display for SubmitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SubmitError::Attachment(path, _) => {
+                write!(f, "failed to read attachment `{}`", path.display())
+            }
+            SubmitError::Request(_) => write!(f, "failed to send request to the ELOG server"),
+            SubmitError::Unconfirmed(reason) => write!(
+                f,
+                "ELOG server might have created the entry anyway: {reason} (check the logbook before submitting again)"
+            ),
+            SubmitError::Rejected(reason) => write!(f, "ELOG server rejected entry: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for SubmitError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SubmitError::Attachment(_, err) => Some(err),
+            SubmitError::Request(err) => Some(err),
+            SubmitError::Unconfirmed(_) | SubmitError::Rejected(_) => None,
+        }
+    }
+}
+
+// ELOG doesn't have an API. We just reproduce the multipart form that the
+// official `elog` command line client (and the web interface) sends when
+// submitting a new entry. On success, the server replies with a redirect to
+// the newly created entry; any other response is an HTML page explaining what
+// went wrong.
+//
+// Return the ID of the new message.
+pub fn submit_entry(
+    entry: &ElogEntry,
+    attributes: &[(String, String)],
+    reply_to: Option<u32>,
+    config: &ElogConfig,
+) -> Result<u32, SubmitError> {
+    let mut form = Form::new()
+        .text("cmd", "Submit")
+        .text("exp", config.logbook.clone())
+        .text("encoding", "plain")
+        // Equivalent to the `-x` flag of the `elog` client.
+        .text("suppress", "1")
+        .text("Text", entry.text.clone());
+    if let Some(id) = reply_to {
+        form = form.text("reply_to", id.to_string());
+    }
+    for (name, value) in attributes {
+        // The server expects spaces in attribute names to be replaced by
+        // underscores in the form field names.
+        form = form.text(name.replace(' ', "_"), value.clone());
+    }
+    for (i, path) in entry.attachments.iter().enumerate() {
+        let part = Part::file(path).map_err(|e| SubmitError::Attachment(path.clone(), e))?;
+        form = form.part(format!("attfile{i}"), part);
+    }
+
+    let resp = reqwest::blocking::Client::builder()
+        .redirect(Policy::none())
+        .build()
+        .map_err(SubmitError::Request)?
+        .post(format!(
+            "http://{}:{}/{}/",
+            config.host, config.port, config.logbook
+        ))
+        .multipart(form)
+        .send()
+        .map_err(|err| {
+            // Anything other than failing to connect could happen after the
+            // server already received the entry.
+            if err.is_connect() || err.is_builder() {
+                SubmitError::Request(err)
+            } else {
+                SubmitError::Unconfirmed(format!("{:#}", anyhow::anyhow!(err)))
+            }
+        })?;
+
+    if resp.status() == StatusCode::FOUND {
+        let location = resp
+            .headers()
+            .get(LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .ok_or_else(|| SubmitError::Unconfirmed(String::from("redirect without location")))?;
+
+        message_id(location)
+    } else {
+        let status = resp.status();
+        let body = resp.text().map_err(SubmitError::Request)?;
+
+        Err(SubmitError::Rejected(rejection_reason(status, &body)))
+    }
+}
+
+// The redirect after a successful submission points to the new entry (e.g.
+// `http://host/Logbook/123`). A failed login redirects back to the logbook with
+// a `fail` query parameter instead. Any other redirect is most likely still a
+// successful submission.
+fn message_id(location: &str) -> Result<u32, SubmitError> {
+    let (path, query) = location.split_once('?').unwrap_or((location, ""));
+    if query
+        .split('&')
+        .any(|param| param.split('=').next() == Some("fail"))
+    {
+        return Err(SubmitError::Rejected(String::from(
+            "invalid user name or password",
+        )));
+    }
+
+    path.trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .and_then(|id| id.parse().ok())
+        .ok_or_else(|| {
+            SubmitError::Unconfirmed(format!("failed to parse message ID from `{location}`"))
+        })
+}
+
+// Same heuristics as the official `elog` client to figure out why an entry
+// was not accepted.
+fn rejection_reason(status: StatusCode, body: &str) -> String {
+    if body.contains("Logbook Selection") {
+        String::from("no such logbook")
+    } else if body.contains("enter password") {
+        String::from("missing or invalid password")
+    } else if body.contains("form name=form1") {
+        String::from("missing or invalid user name/password")
+    } else if let Some(offset) = body.find("Error: Attribute") {
+        let message = &body[offset + "Error: ".len()..];
+        let message = message.split(['\n', '\r']).next().unwrap_or(message);
+
+        format!("missing required attribute ({})", strip_html_tags(message))
+    } else {
+        format!("unexpected response ({status})")
+    }
+}
+
+fn strip_html_tags(html: &str) -> String {
+    let mut text = String::new();
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            c if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+
+    text.trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejection(result: Result<u32, SubmitError>) -> String {
+        match result {
+            Err(SubmitError::Rejected(reason)) => reason,
+            other => panic!("expected a rejection, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn message_id_from_location() {
+        assert_eq!(message_id("http://localhost:8080/Test/123").unwrap(), 123);
+        assert_eq!(message_id("http://localhost:8080/Test/123/").unwrap(), 123);
+        assert_eq!(message_id("/Test/45?suppress=1").unwrap(), 45);
+        assert_eq!(
+            rejection(message_id("http://localhost:8080/Test/?fail=1")),
+            "invalid user name or password"
+        );
+        assert_eq!(
+            rejection(message_id("/Test/?cmd=Submit&fail=1")),
+            "invalid user name or password"
+        );
+        // Logbook names (and hosts) can contain anything.
+        assert_eq!(
+            message_id("http://failover:8080/positron_failures/7").unwrap(),
+            7
+        );
+        assert_eq!(message_id("/positron_failures/8?suppress=1").unwrap(), 8);
+        // The entry was most likely created anyway.
+        assert!(matches!(
+            message_id("http://localhost:8080/Test/"),
+            Err(SubmitError::Unconfirmed(_))
+        ));
+    }
+
+    #[test]
+    fn rejection_reasons() {
+        let reason = |body| rejection_reason(StatusCode::OK, body);
+        assert_eq!(
+            reason("<title>ELOG Logbook Selection</title>"),
+            "no such logbook"
+        );
+        assert_eq!(
+            reason("Please enter password"),
+            "missing or invalid password"
+        );
+        assert_eq!(
+            reason("<form name=form1 method=\"POST\">"),
+            "missing or invalid user name/password"
+        );
+        assert_eq!(
+            reason("<b>Error: Attribute <i>Author</i> not supplied.</b>\n<p>Back</p>"),
+            "missing required attribute (Attribute Author not supplied.)"
+        );
+        assert_eq!(
+            rejection_reason(StatusCode::INTERNAL_SERVER_ERROR, ""),
+            "unexpected response (500 Internal Server Error)"
+        );
+    }
+
+    #[test]
+    fn html_tags_are_stripped() {
+        assert_eq!(
+            strip_html_tags(" <b>bold</b> and <i>italic</i> "),
+            "bold and italic"
+        );
+        assert_eq!(strip_html_tags("no tags"), "no tags");
+        assert_eq!(strip_html_tags("<br/>"), "");
+    }
+}
@@ -1,19 +1,85 @@
+use crate::config::{RecordTimes, SummaryFormat};
 use crate::data_handler::SpillLog;
 use anyhow::{Context, Result};
+use jiff::{SignedDuration, Zoned};
 use std::io::Write;
 use std::path::PathBuf;
 
-pub fn spill_log_summary(spill_log: &SpillLog, columns: &[String]) -> Result<PathBuf> {
-    let mut builder = tabled::builder::Builder::new();
+// Format seconds since the start of the run (as a local time if `wall_clock`).
+pub fn format_run_time(seconds: f64, run_start: &Zoned, wall_clock: bool) -> String {
+    if !wall_clock {
+        return seconds.to_string();
+    }
+
+    // e.g. `NaN` is a valid time in the spill log CSV.
+    SignedDuration::try_from_secs_f64(seconds)
+        .ok()
+        .and_then(|duration| run_start.checked_add(duration).ok())
+        .map_or_else(
+            || seconds.to_string(),
+            |time| time.strftime("%T%.3f").to_string(),
+        )
+}
+
+pub fn format_seconds(seconds: f64) -> String {
+    format!("{seconds:.3} s")
+}
+
+pub fn spill_log_summary(
+    spill_log: &SpillLog,
+    columns: &[String],
+    times: RecordTimes,
+    run_start: &Zoned,
+    format: SummaryFormat,
+) -> Result<PathBuf> {
+    let rows = summary_rows(spill_log, columns, times, run_start);
+    let (contents, suffix) = match format {
+        SummaryFormat::Text => (
+            tabled::builder::Builder::from_iter(rows)
+                .build()
+                .to_string(),
+            ".txt",
+        ),
+        SummaryFormat::Csv => (to_csv(rows)?, ".csv"),
+        SummaryFormat::Html => (to_html(rows), ".html"),
+        SummaryFormat::Markdown => (to_markdown(rows), ".md"),
+    };
+
+    let mut temp = tempfile::Builder::new()
+        .keep(true)
+        .suffix(suffix)
+        .tempfile()
+        .context("failed to create temporary file")?;
+    temp.write_all(contents.as_bytes())
+        .context("failed to write to temporary file")?;
+
+    Ok(temp.path().to_owned())
+}
+
+// The first row is the header.
+fn summary_rows(
+    spill_log: &SpillLog,
+    columns: &[String],
+    times: RecordTimes,
+    run_start: &Zoned,
+) -> Vec<Vec<String>> {
+    let mut rows = Vec::new();
 
     let mut header = vec![
         "Event".to_string(),
         "Start time".to_string(),
         "Stop time".to_string(),
     ];
+    if times.duration {
+        header.push("Duration".to_string());
+    }
+    if times.gap {
+        header.push("Gap".to_string());
+    }
     header.extend(columns.iter().cloned());
-    builder.push_record(header);
+    rows.push(header);
 
+    let mut previous_stop = None;
     for record in spill_log.records.iter() {
         let mut row = vec![
             format!(
@@ -21,9 +87,18 @@ pub fn spill_log_summary(spill_log: &SpillLog, columns: &[String]) -> Result<Pat
                 record.sequencer_name.to_uppercase(),
                 record.event_description
             ),
-            record.start_time.to_string(),
-            record.stop_time.to_string(),
+            format_run_time(record.start_time, run_start, times.wall_clock),
+            format_run_time(record.stop_time, run_start, times.wall_clock),
         ];
+        if times.duration {
+            row.push(format_seconds(record.stop_time - record.start_time));
+        }
+        if times.gap {
+            row.push(previous_stop.map_or_else(String::new, |previous_stop| {
+                format_seconds(record.start_time - previous_stop)
+            }));
+        }
+        previous_stop = Some(record.stop_time);
 
         for column in columns.iter() {
             row.push(
@@ -34,16 +109,52 @@ pub fn spill_log_summary(spill_log: &SpillLog, columns: &[String]) -> Result<Pat
             );
         }
 
-        builder.push_record(row);
+        rows.push(row);
     }
 
-    let mut temp = tempfile::Builder::new()
-        .keep(true)
-        .suffix(".txt")
-        .tempfile()
-        .context("failed to create temporary file")?;
-    temp.write_all(builder.build().to_string().as_bytes())
-        .context("failed to write to temporary file")?;
+    rows
+}
 
-    Ok(temp.path().to_owned())
+fn to_csv(rows: Vec<Vec<String>>) -> Result<String> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for row in rows {
+        writer
+            .write_record(row)
+            .context("failed to write csv record")?;
+    }
+    let bytes = writer.into_inner().context("failed to flush csv writer")?;
+
+    String::from_utf8(bytes).context("csv is not valid utf-8")
+}
+
+fn to_html(rows: Vec<Vec<String>>) -> String {
+    let escape = |cell: &str| {
+        cell.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;")
+    };
+
+    let mut html = String::from("<table>\n");
+    for (i, row) in rows.iter().enumerate() {
+        let tag = if i == 0 { "th" } else { "td" };
+        html.push_str("  <tr>");
+        for cell in row {
+            html.push_str(&format!("<{tag}>{}</{tag}>", escape(cell)));
+        }
+        html.push_str("</tr>\n");
+    }
+    html.push_str("</table>\n");
+
+    html
+}
+
+fn to_markdown(rows: Vec<Vec<String>>) -> String {
+    let rows = rows
+        .into_iter()
+        .map(|row| row.into_iter().map(|cell| cell.replace('|', "\\|")));
+    let mut table = tabled::builder::Builder::from_iter(rows).build();
+    table.with(tabled::settings::Style::markdown());
+
+    table.to_string()
 }
@@ -0,0 +1,214 @@
+use crate::{data_dir, data_handler_client, ledger_file, log_run, EntryOptions, WatchArgs};
+use alpha_g_elogger::attributes::{missing_attributes, validate_attributes};
+use alpha_g_elogger::cache::CacheMode;
+use alpha_g_elogger::config::Config;
+use alpha_g_elogger::data_handler::{run_has_stopped, DataHandlerError};
+use alpha_g_elogger::ledger::Ledger;
+use alpha_g_elogger::submit::SubmitError;
+use anyhow::{ensure, Context, Result};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+// Failures that would most likely happen again (e.g. the ELOG server rejecting
+// the entry) are only retried a few times before moving on to the next run.
+const MAX_ATTEMPTS: u32 = 3;
+
+// The last run successfully logged by `watch` is persisted across restarts.
+fn state_file() -> PathBuf {
+    data_dir().join("watch_last_run")
+}
+
+fn read_last_run() -> Result<Option<u32>> {
+    match std::fs::read_to_string(state_file()) {
+        Ok(contents) => Ok(Some(
+            contents
+                .trim()
+                .parse()
+                .context("failed to parse last logged run")?,
+        )),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).context("failed to read last logged run"),
+    }
+}
+
+fn write_last_run(run_number: u32) -> Result<()> {
+    let path = state_file();
+    std::fs::create_dir_all(path.parent().unwrap()).context("failed to create data directory")?;
+    std::fs::write(&path, run_number.to_string())
+        .with_context(|| format!("failed to write `{}`", path.display()))
+}
+
+fn log_message(message: &str) {
+    println!("[{}] {message}", jiff::Zoned::now().strftime("%F %T"));
+}
+
+// A run is considered to have ended once the data handler can provide its
+// final ODB and the ODB reports the run as stopped.
+fn has_run_ended(run_number: u32, config: &Config, cache: CacheMode) -> Result<bool> {
+    let mut client = data_handler_client(config, cache)?;
+    if !client.is_ready(run_number)? {
+        return Ok(false);
+    }
+    let Ok(final_odb) = client.final_odb(run_number) else {
+        return Ok(false);
+    };
+    Ok(run_has_stopped(&final_odb))
+}
+
+pub fn watch(config: &Config, args: &WatchArgs, cache: CacheMode) -> Result<()> {
+    let watch_config = config
+        .watch
+        .as_ref()
+        .context("missing `watch` section in configuration")?;
+    let attributes = watch_config
+        .attributes
+        .iter()
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect::<Vec<_>>();
+    let profile = config.profile()?;
+    validate_attributes(&profile.attributes, &attributes)
+        .context("invalid `watch.attributes` in configuration")?;
+    let missing = missing_attributes(&profile.attributes, &attributes);
+    ensure!(
+        missing.is_empty(),
+        "missing values in `watch.attributes` for: {}",
+        missing.join(", ")
+    );
+    let options = EntryOptions {
+        reply_to: None,
+        attributes,
+        dry_run: false,
+        force: false,
+        edit: Some(false),
+        // Runs are only logged once `has_run_ended`, so they are always ready.
+        wait: false,
+        cache,
+    };
+    let mut ledger = Ledger::open(ledger_file()).context("failed to open submission ledger")?;
+
+    let mut next_run = match args.from {
+        Some(run_number) => run_number,
+        None => read_last_run()?
+            .map(|run_number| run_number + 1)
+            .context("no run has been logged by `watch` before (use `--from` instead)")?,
+    };
+    let poll_interval = Duration::from_secs(args.poll_interval);
+    let skip_missing_after = Duration::from_secs(args.skip_missing_after);
+    // Consecutive failures to log `next_run` that are not transient.
+    let mut failures = 0;
+    // When `next_run + 1` was first seen to have ended while `next_run` was
+    // still not ready.
+    let mut successor_ended: Option<Instant> = None;
+
+    log_message(&format!("Waiting for run {next_run} to end..."));
+    loop {
+        if let Some(previous) = ledger.find(next_run, &config.elog.logbook) {
+            log_message(&format!("Skipping run {next_run} ({previous})"));
+            next_run += 1;
+            (failures, successor_ended) = (0, None);
+            continue;
+        }
+
+        match has_run_ended(next_run, config, cache) {
+            Ok(true) => {
+                let done = match log_run(next_run, config, &options, &mut ledger, None) {
+                    Ok(_) => true,
+                    // Retrying could create a duplicate entry.
+                    Err(err) if is_unconfirmed(&err) => {
+                        log_message(&format!(
+                            "Error: failed to log run {next_run} (will not retry): {err:#}"
+                        ));
+                        true
+                    }
+                    Err(err) if is_transient(&err) => {
+                        log_message(&format!(
+                            "Error: failed to log run {next_run} (will retry): {err:#}"
+                        ));
+                        false
+                    }
+                    Err(err) => {
+                        failures += 1;
+                        if failures < MAX_ATTEMPTS {
+                            log_message(&format!(
+                                "Error: failed to log run {next_run} (attempt {failures} of {MAX_ATTEMPTS}, will retry): {err:#}"
+                            ));
+                            false
+                        } else {
+                            log_message(&format!(
+                                "Error: failed to log run {next_run} (giving up after {failures} attempts): {err:#}"
+                            ));
+                            true
+                        }
+                    }
+                };
+                if done {
+                    if let Err(err) = write_last_run(next_run) {
+                        log_message(&format!("Warning: {err:#}"));
+                    }
+                    next_run += 1;
+                    (failures, successor_ended) = (0, None);
+                    log_message(&format!("Waiting for run {next_run} to end..."));
+                    continue;
+                }
+            }
+            // A run number might never exist (e.g. MIDAS crashed while
+            // starting a run). Don't get stuck waiting for it forever, but
+            // the data handler can't tell that apart from a run that is just
+            // slow to process. Give it some time before skipping it.
+            Ok(false) => {
+                if let Ok(true) = has_run_ended(next_run + 1, config, cache) {
+                    let since = *successor_ended.get_or_insert_with(|| {
+                        log_message(&format!(
+                            "Run {} has already ended (run {next_run} will be skipped if it is still not ready in {}s)",
+                            next_run + 1,
+                            skip_missing_after.as_secs()
+                        ));
+                        Instant::now()
+                    });
+                    if since.elapsed() >= skip_missing_after {
+                        log_message(&format!(
+                            "Skipping run {next_run} (run {} has already ended)",
+                            next_run + 1
+                        ));
+                        next_run += 1;
+                        (failures, successor_ended) = (0, None);
+                        continue;
+                    }
+                }
+            }
+            Err(err) => log_message(&format!(
+                "Error: failed to query data handler (will retry): {err:#}"
+            )),
+        }
+
+        std::thread::sleep(poll_interval);
+    }
+}
+
+// Failures that are expected to go away on their own (e.g. the data handler or
+// the ELOG server being temporarily unreachable).
+fn is_transient(err: &anyhow::Error) -> bool {
+    err.chain().any(|err| {
+        matches!(
+            err.downcast_ref::<DataHandlerError>(),
+            Some(
+                DataHandlerError::NotReady
+                    | DataHandlerError::TimedOut
+                    | DataHandlerError::Server(_)
+                    | DataHandlerError::Connection(_)
+            )
+        ) || matches!(
+            err.downcast_ref::<SubmitError>(),
+            Some(SubmitError::Request(_))
+        )
+    })
+}
+
+fn is_unconfirmed(err: &anyhow::Error) -> bool {
+    err.chain().any(|err| {
+        matches!(
+            err.downcast_ref::<SubmitError>(),
+            Some(SubmitError::Unconfirmed(_))
+        )
+    })
+}
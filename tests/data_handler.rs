@@ -0,0 +1,55 @@
+// Use the data handler client directly (instead of through the elogger binary)
+// against a mock data handler.
+
+mod mock_data_handler;
+
+use alpha_g_elogger::config::DataHandlerConfig;
+use alpha_g_elogger::data_handler::DataHandlerClient;
+use indicatif::ProgressBar;
+use mock_data_handler::{Fixtures, MockDataHandler, Reply};
+
+const RUN_NUMBER: u32 = 12001;
+
+fn client(server: &MockDataHandler) -> DataHandlerClient {
+    let config: DataHandlerConfig = toml::from_str(&format!(
+        r#"
+host = "127.0.0.1"
+port = {}
+connect_timeout = 2
+max_retries = 0
+"#,
+        server.port()
+    ))
+    .unwrap();
+
+    DataHandlerClient::new(config).unwrap()
+}
+
+#[test]
+fn progress_messages_are_shown_next_to_their_request() {
+    let mut fixtures = Fixtures::default();
+    fixtures.ready_runs.insert(RUN_NUMBER);
+    fixtures.final_odb.insert(
+        RUN_NUMBER,
+        Reply::Data(br#"{ "Runinfo": { "State": 1 } }"#.to_vec()),
+    );
+    fixtures.spill_log.insert(
+        RUN_NUMBER,
+        Reply::Data(b"sequencer_name,event_description,start_time,stop_time\n".to_vec()),
+    );
+    fixtures.progress_messages = vec![
+        String::from("Reading MIDAS file..."),
+        String::from("Done\n"),
+    ];
+    let server = MockDataHandler::start(fixtures);
+    let mut client = client(&server);
+    let progress_bar = ProgressBar::hidden();
+    client.set_progress_bar(progress_bar.clone());
+
+    client.spill_log(RUN_NUMBER).unwrap();
+    assert_eq!(progress_bar.message(), "Spill log: Done");
+
+    client.report("Getting final ODB...");
+    client.final_odb(RUN_NUMBER).unwrap();
+    assert_eq!(progress_bar.message(), "Final ODB: Done");
+}
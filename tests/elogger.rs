@@ -0,0 +1,1168 @@
+// Drive the whole elogger binary (in dry run mode) against a mock data handler.
+// The cache and data directories of each run are inside a temporary directory,
+// so the real cache and ledger of the user are never touched.
+
+mod mock_data_handler;
+mod mock_elog;
+
+use mock_data_handler::{Fixtures, MockDataHandler, Reply};
+use mock_elog::MockElog;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Output, Stdio};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+const RUN_NUMBER: u32 = 12001;
+
+const FINAL_ODB: &str = r#"{
+    "Runinfo": {
+        "State": 1,
+        "Start time binary": "0x66000000",
+        "Stop time binary": "0x66000E10"
+    },
+    "Experiment": {
+        "Edit on start": { "Comment": "" }
+    },
+    "Equipment": {
+        "cb01": { "Settings": { "names": ["SIS", "TPC"] } },
+        "cb02": { "Settings": { "names": [] } },
+        "cb03": { "Settings": { "names": [] } },
+        "cb04": { "Settings": { "names": [] } }
+    }
+}"#;
+
+const SPILL_LOG: &str = "\
+sequencer_name,event_description,start_time,stop_time,SIS,TPC
+cat,Hot Dump 1,1.5,2.25,10,20
+atm,Ignored,3.0,4.0,1,2
+cat,Hot Dump 2,5.0,6.5,30,40
+";
+
+fn fixtures() -> Fixtures {
+    let mut fixtures = Fixtures::default();
+    fixtures.ready_runs.insert(RUN_NUMBER);
+    fixtures
+        .final_odb
+        .insert(RUN_NUMBER, Reply::Data(FINAL_ODB.into()));
+    fixtures
+        .spill_log
+        .insert(RUN_NUMBER, Reply::Data(SPILL_LOG.into()));
+    for (channel, name) in [(0, "SIS"), (1, "TPC")] {
+        fixtures.plots.insert(
+            (RUN_NUMBER, String::from("cb01"), channel),
+            Reply::Data(format!("%PDF {name}").into()),
+        );
+    }
+
+    fixtures
+}
+
+struct Elogger {
+    home: TempDir,
+    config: PathBuf,
+}
+
+impl Elogger {
+    // `data_handler` is extra configuration for the `data_handler` section.
+    fn new(server: &MockDataHandler, data_handler: &str) -> Self {
+        Self::with_config(server, "", data_handler)
+    }
+
+    // `top_level` is extra configuration at the top level of the file.
+    fn with_config(server: &MockDataHandler, top_level: &str, data_handler: &str) -> Self {
+        let home = TempDir::new().unwrap();
+        let config = home.path().join("Elogger.toml");
+        std::fs::write(
+            &config,
+            format!(
+                r#"
+spill_log_columns = []
+{top_level}
+
+[[rules]]
+sequencer_name = "cat"
+event_description = {{ glob = "Hot Dump *" }}
+config = {{ chronobox_table = {{ channel_names = ["SIS", "TPC"], include_attachments = true }} }}
+
+[elog]
+host = "127.0.0.1"
+port = 1
+logbook = "Test"
+
+[elog.logbooks.Test]
+attributes = [{{ name = "Run", source = "run_number" }}]
+
+[data_handler]
+host = "127.0.0.1"
+port = {}
+connect_timeout = 2
+max_retries = 0
+initial_backoff_ms = 10
+{data_handler}
+"#,
+                server.port()
+            ),
+        )
+        .unwrap();
+
+        Self { home, config }
+    }
+
+    // Replace `from` with `to` in the configuration file.
+    fn edit_config(&self, from: &str, to: &str) {
+        let config = std::fs::read_to_string(&self.config).unwrap();
+        assert!(
+            config.contains(from),
+            "`{from}` is not in the configuration"
+        );
+        std::fs::write(&self.config, config.replace(from, to)).unwrap();
+    }
+
+    // Submit entries to `elog` instead of the (unreachable) default server.
+    fn submit_to(&self, elog: &MockElog) {
+        self.edit_config("port = 1\n", &format!("port = {}\n", elog.port()));
+    }
+
+    fn data_dir(&self) -> PathBuf {
+        self.home.path().join("data")
+    }
+
+    fn output_dir(&self) -> PathBuf {
+        self.home.path().join("output")
+    }
+
+    // The elogger with nothing but the configuration file and `args`.
+    fn command(&self, args: &[&str]) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_alpha-g-elogger"));
+        command
+            .env("ALPHA_G_ELOGGER_CACHE_DIR", self.home.path().join("cache"))
+            .env("ALPHA_G_ELOGGER_DATA_DIR", self.data_dir())
+            .arg("--config-file")
+            .arg(&self.config)
+            .args(args);
+
+        command
+    }
+
+    // Log `runs` in dry run mode, and write the entries to `output_dir`.
+    fn dry_run(&self, runs: &str, extra_args: &[&str]) -> Output {
+        self.command(&[
+            runs,
+            "--dry-run",
+            "--new-thread",
+            "--no-edit",
+            "--output-dir",
+        ])
+        .arg(self.output_dir())
+        .args(extra_args)
+        .output()
+        .unwrap()
+    }
+
+    fn run(&self, extra_args: &[&str]) -> Output {
+        self.dry_run(&RUN_NUMBER.to_string(), extra_args)
+    }
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+fn entry_text(dir: &Path) -> String {
+    std::fs::read_to_string(dir.join("entry.txt")).unwrap()
+}
+
+// Contents of the attachment referenced as `elog:/N`.
+fn attachment(dir: &Path, n: usize) -> String {
+    let prefix = format!("{n}_");
+    let path = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| {
+            path.file_name()
+                .unwrap()
+                .to_string_lossy()
+                .starts_with(&prefix)
+        })
+        .unwrap_or_else(|| panic!("missing attachment {n}"));
+
+    std::fs::read_to_string(path).unwrap()
+}
+
+#[test]
+fn builds_entry_from_data_handler() {
+    let server = MockDataHandler::start(fixtures());
+    let elogger = Elogger::new(&server, "");
+
+    let output = elogger.run(&["--no-cache"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let dir = elogger.output_dir();
+    let text = entry_text(&dir);
+    assert!(text.contains("CAT - Hot Dump 1"));
+    assert!(text.contains("CAT - Hot Dump 2"));
+    assert!(!text.contains("Ignored"));
+    // The spill log summary is the first attachment, followed by the plots of
+    // each record in order.
+    for (n, expected) in [(2, "SIS"), (3, "TPC"), (4, "SIS"), (5, "TPC")] {
+        assert!(text.contains(&format!("elog:/{n}")));
+        assert_eq!(attachment(&dir, n), format!("%PDF {expected}"));
+    }
+
+    let plot_requests = server
+        .requests()
+        .iter()
+        .filter(|request| request.get("ChronoboxPlot").is_some())
+        .count();
+    assert_eq!(plot_requests, 4);
+}
+
+#[test]
+fn delayed_plots_keep_their_order() {
+    let mut fixtures = fixtures();
+    for plot in fixtures.plots.values_mut() {
+        *plot = Reply::Delayed(Duration::from_millis(100), Box::new(plot.clone()));
+    }
+    let server = MockDataHandler::start(fixtures);
+    let elogger = Elogger::new(&server, "max_concurrent_requests = 3");
+
+    let output = elogger.run(&["--no-cache"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    // Results are slotted back in the original order.
+    let dir = elogger.output_dir();
+    for (n, expected) in [(2, "SIS"), (3, "TPC"), (4, "SIS"), (5, "TPC")] {
+        assert_eq!(attachment(&dir, n), format!("%PDF {expected}"));
+    }
+}
+
+#[test]
+fn plots_replied_in_reverse_order_keep_their_order() {
+    let mut fixtures = fixtures();
+    fixtures.concurrent = true;
+    // The first request gets the slowest reply.
+    let plots = [
+        ("SIS", 0, "1.5"),
+        ("TPC", 1, "1.5"),
+        ("SIS", 0, "5.0"),
+        ("TPC", 1, "5.0"),
+    ];
+    for (i, (name, channel, t_min)) in plots.into_iter().enumerate() {
+        fixtures.timed_plots.insert(
+            (
+                RUN_NUMBER,
+                String::from("cb01"),
+                channel,
+                String::from(t_min),
+            ),
+            Reply::Delayed(
+                Duration::from_millis(100 * (4 - i as u64)),
+                Box::new(Reply::Data(format!("%PDF {name} {t_min}").into())),
+            ),
+        );
+    }
+    let server = MockDataHandler::start(fixtures);
+    let elogger = Elogger::new(&server, "max_concurrent_requests = 4");
+
+    let output = elogger.run(&["--no-cache"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let replied = server
+        .replies()
+        .iter()
+        .filter_map(|request| request.get("ChronoboxPlot"))
+        .map(|plot| plot["args"]["t_min"].to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(replied, ["5.0", "5.0", "1.5", "1.5"]);
+
+    let dir = elogger.output_dir();
+    for (i, (name, _, t_min)) in plots.into_iter().enumerate() {
+        assert_eq!(attachment(&dir, i + 2), format!("%PDF {name} {t_min}"));
+    }
+}
+
+#[test]
+fn stalled_plot_times_out_while_others_complete() {
+    let mut fixtures = fixtures();
+    fixtures.concurrent = true;
+    // The first plot never arrives, and the others keep arriving in between.
+    let plots = [
+        ("SIS", 0, "1.5", 30_000),
+        ("TPC", 1, "1.5", 600),
+        ("SIS", 0, "5.0", 1200),
+        ("TPC", 1, "5.0", 1800),
+    ];
+    for (name, channel, t_min, delay) in plots {
+        fixtures.timed_plots.insert(
+            (
+                RUN_NUMBER,
+                String::from("cb01"),
+                channel,
+                String::from(t_min),
+            ),
+            Reply::Delayed(
+                Duration::from_millis(delay),
+                Box::new(Reply::Data(format!("%PDF {name}").into())),
+            ),
+        );
+    }
+    let server = MockDataHandler::start(fixtures);
+    let elogger = Elogger::new(&server, "max_concurrent_requests = 4\ntimeout = 2");
+
+    let start = Instant::now();
+    let output = elogger.run(&["--no-cache"]);
+    let elapsed = start.elapsed();
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let dir = elogger.output_dir();
+    assert_eq!(entry_text(&dir).matches("<DATA_HANDLER_ERROR>").count(), 1);
+    for (n, expected) in [(2, "TPC"), (3, "SIS"), (4, "TPC")] {
+        assert_eq!(attachment(&dir, n), format!("%PDF {expected}"));
+    }
+    // The stalled plot is given up on 2 seconds after it was requested, no
+    // matter how many other plots arrived in the meantime.
+    assert!(elapsed < Duration::from_secs(3), "{elapsed:?}");
+}
+
+#[test]
+fn plot_error_is_marked_in_entry() {
+    let mut fixtures = fixtures();
+    fixtures.plots.insert(
+        (RUN_NUMBER, String::from("cb01"), 1),
+        Reply::Error(String::from("plot failed")),
+    );
+    let server = MockDataHandler::start(fixtures);
+    let elogger = Elogger::new(&server, "");
+
+    let output = elogger.run(&["--no-cache"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let dir = elogger.output_dir();
+    let text = entry_text(&dir);
+    assert_eq!(text.matches("<DATA_HANDLER_ERROR>").count(), 2);
+    assert_eq!(attachment(&dir, 2), "%PDF SIS");
+    assert_eq!(attachment(&dir, 3), "%PDF SIS");
+}
+
+#[test]
+fn progress_messages_are_ignored_in_replies() {
+    let mut fixtures = fixtures();
+    fixtures.progress_messages = vec![String::from("Reading MIDAS file..."), String::from("Done")];
+    let server = MockDataHandler::start(fixtures);
+    let elogger = Elogger::new(&server, "");
+
+    let output = elogger.run(&["--no-cache"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    // Same entry as without any progress messages.
+    let quiet_server = MockDataHandler::start(self::fixtures());
+    let quiet_elogger = Elogger::new(&quiet_server, "");
+    let output = quiet_elogger.run(&["--no-cache"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let dir = elogger.output_dir();
+    let quiet_dir = quiet_elogger.output_dir();
+    assert_eq!(entry_text(&dir), entry_text(&quiet_dir));
+    for n in 1..=5 {
+        assert_eq!(attachment(&dir, n), attachment(&quiet_dir, n));
+    }
+}
+
+#[test]
+fn configurations_without_profiles_use_the_default_ones() {
+    let server = MockDataHandler::start(fixtures());
+    let elogger = Elogger::new(&server, "");
+    elogger.edit_config(
+        "logbook = \"Test\"\n\n[elog.logbooks.Test]\nattributes = [{ name = \"Run\", source = \"run_number\" }]\n",
+        "logbook = \"DataLog\"\n",
+    );
+
+    let output = elogger.run(&["--no-cache", "--author", "Someone", "--type", "Pbar Log"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let stdout = stdout(&output);
+    for expected in [
+        "Logbook: DataLog",
+        "Author: Someone",
+        "Type: Pbar Log",
+        "Run: 12001",
+        "Subject: MISSING START-RUN COMMENT",
+    ] {
+        assert!(
+            stdout.contains(expected),
+            "missing `{expected}` in:\n{stdout}"
+        );
+    }
+
+    // Only the configured types are accepted.
+    let output = elogger.run(&["--no-cache", "--author", "Someone", "--type", "Other"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("invalid value `Other` for attribute `Type`"));
+}
+
+#[test]
+fn profile_without_attributes_is_rejected() {
+    let server = MockDataHandler::start(fixtures());
+    let elogger = Elogger::new(&server, "");
+    elogger.edit_config(
+        "attributes = [{ name = \"Run\", source = \"run_number\" }]",
+        "attributes = []",
+    );
+
+    let output = elogger.run(&["--no-cache"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("a logbook profile needs at least one attribute"));
+    assert!(server.http_requests().is_empty());
+}
+
+#[test]
+fn missing_values_fail_without_terminal() {
+    let server = MockDataHandler::start(fixtures());
+    let elogger = Elogger::new(&server, "");
+    elogger.edit_config(
+        "attributes = [",
+        r#"attributes = [{ name = "Author", source = "prompt" }, "#,
+    );
+
+    // Stdin is never a terminal in tests.
+    let output = elogger
+        .command(&[&RUN_NUMBER.to_string(), "--dry-run", "--no-edit"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains(
+        "stdin is not a terminal and the following values were not provided: \
+         --reply-to <MESSAGE_ID> (or --new-thread), --attribute Author=<VALUE>"
+    ));
+    assert!(server.http_requests().is_empty());
+
+    // Nothing is asked if every value is provided.
+    let output = elogger
+        .command(&[&RUN_NUMBER.to_string(), "--dry-run", "--reply-to", "7"])
+        .args(["--author", "Someone", "--no-cache"])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    let stdout = stdout(&output);
+    assert!(stdout.contains("Reply to: 7"));
+    assert!(stdout.contains("Author: Someone"));
+}
+
+#[test]
+fn multiple_runs_are_logged_and_summarized() {
+    let mut fixtures = fixtures();
+    let next_run = RUN_NUMBER + 1;
+    fixtures.ready_runs.insert(next_run);
+    fixtures
+        .final_odb
+        .insert(next_run, Reply::Data(FINAL_ODB.into()));
+    fixtures.spill_log.insert(
+        next_run,
+        Reply::Data(SPILL_LOG.replace("Hot Dump 1", "Hot Dump 3").into()),
+    );
+    for channel in [0, 1] {
+        let plot = fixtures.plots[&(RUN_NUMBER, String::from("cb01"), channel)].clone();
+        fixtures
+            .plots
+            .insert((next_run, String::from("cb01"), channel), plot);
+    }
+    // The last run is not ready.
+    let server = MockDataHandler::start(fixtures);
+    let elogger = Elogger::new(&server, "");
+
+    let output = elogger.dry_run("12001-12003,12001", &["--no-cache"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("failed to log 1 out of 3 runs"));
+
+    let stdout = stdout(&output);
+    assert_eq!(stdout.matches("===== Run ").count(), 3);
+    let summary = stdout.split("Summary:\n").nth(1).unwrap();
+    assert!(summary.starts_with(
+        "  Run 12001: OK (dry run)\n  Run 12002: OK (dry run)\n  Run 12003: FAILED ("
+    ));
+    assert!(summary.contains("data handler is not ready"));
+
+    // One directory per run.
+    let dir = elogger.output_dir();
+    assert!(entry_text(&dir.join("12001")).contains("CAT - Hot Dump 1"));
+    assert!(entry_text(&dir.join("12002")).contains("CAT - Hot Dump 3"));
+    assert!(!dir.join("12003").exists());
+}
+
+#[test]
+fn huge_run_range_is_rejected() {
+    let server = MockDataHandler::start(fixtures());
+    let elogger = Elogger::new(&server, "");
+
+    let output = elogger.run(&["1-3000000000"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("has more than 10000 runs"));
+    assert!(server.http_requests().is_empty());
+}
+
+#[test]
+fn run_not_ready_fails() {
+    let mut fixtures = fixtures();
+    fixtures.ready_runs.clear();
+    let server = MockDataHandler::start(fixtures);
+    let elogger = Elogger::new(&server, "");
+
+    let output = elogger.run(&["--no-cache"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("data handler is not ready"));
+    // Nothing can be requested over the websocket before the run is ready.
+    assert!(server.requests().is_empty());
+}
+
+#[test]
+fn wait_gives_up_after_deadline() {
+    let mut fixtures = fixtures();
+    fixtures.ready_runs.clear();
+    let server = MockDataHandler::start(fixtures);
+    let elogger = Elogger::new(&server, "wait_poll_interval = 1\nwait_deadline = 2");
+
+    let output = elogger.run(&["--no-cache", "--wait"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("gave up waiting for run"));
+    assert!(server.http_requests().len() >= 2);
+}
+
+#[test]
+fn wait_succeeds_once_run_is_ready() {
+    let mut fixtures = fixtures();
+    fixtures.ready_runs.clear();
+    let server = MockDataHandler::start(fixtures);
+    let elogger = Elogger::new(&server, "wait_poll_interval = 1\nwait_deadline = 30");
+
+    let child = elogger
+        .command(&[
+            &RUN_NUMBER.to_string(),
+            "--dry-run",
+            "--new-thread",
+            "--no-edit",
+        ])
+        .args(["--no-cache", "--wait"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    wait_for("the first poll", || !server.http_requests().is_empty());
+    server.make_ready(RUN_NUMBER);
+    let output = child.wait_with_output().unwrap();
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("CAT - Hot Dump 1"));
+    let polls = server
+        .http_requests()
+        .iter()
+        .filter(|path| *path == "/12001")
+        .count();
+    assert!(polls >= 2, "{polls} polls");
+}
+
+#[test]
+fn data_handler_error_fails() {
+    let mut fixtures = fixtures();
+    fixtures.final_odb.insert(
+        RUN_NUMBER,
+        Reply::Error(String::from("failed to open MIDAS file")),
+    );
+    let server = MockDataHandler::start(fixtures);
+    let elogger = Elogger::new(&server, "");
+
+    let output = elogger.run(&["--no-cache"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("failed to open MIDAS file"));
+}
+
+#[test]
+fn malformed_spill_log_fails() {
+    let mut fixtures = fixtures();
+    fixtures.spill_log.insert(
+        RUN_NUMBER,
+        Reply::Data(
+            "sequencer_name,event_description,start_time,stop_time\ncat,Hot Dump 1,oops,2\n".into(),
+        ),
+    );
+    let server = MockDataHandler::start(fixtures);
+    let elogger = Elogger::new(&server, "");
+
+    let output = elogger.run(&["--no-cache"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("failed to parse spill log"));
+}
+
+#[test]
+fn slow_reply_times_out() {
+    let mut fixtures = fixtures();
+    fixtures.final_odb.insert(
+        RUN_NUMBER,
+        Reply::Delayed(
+            Duration::from_secs(3),
+            Box::new(Reply::Data(FINAL_ODB.into())),
+        ),
+    );
+    let server = MockDataHandler::start(fixtures);
+    let elogger = Elogger::new(&server, "read_timeout = 1");
+
+    let output = elogger.run(&["--no-cache"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("timed out"));
+}
+
+#[test]
+fn disconnect_fails() {
+    let mut fixtures = fixtures();
+    fixtures.spill_log.insert(RUN_NUMBER, Reply::Disconnect);
+    let server = MockDataHandler::start(fixtures);
+    let elogger = Elogger::new(&server, "");
+
+    let output = elogger.run(&["--no-cache"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("failed to request spill log"));
+}
+
+#[test]
+fn plots_are_retried_after_disconnect() {
+    let mut fixtures = fixtures();
+    fixtures
+        .plots
+        .insert((RUN_NUMBER, String::from("cb01"), 1), Reply::Disconnect);
+    let server = MockDataHandler::start(fixtures);
+    let elogger = Elogger::new(&server, "");
+    elogger.edit_config("max_retries = 0", "max_retries = 1");
+
+    let output = elogger.run(&["--no-cache"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let tpc_requests = server
+        .requests()
+        .iter()
+        .filter(|request| request["ChronoboxPlot"]["args"]["channel_number"] == 1)
+        .count();
+    assert_eq!(tpc_requests, 2);
+    assert_eq!(attachment(&elogger.output_dir(), 2), "%PDF SIS");
+}
+
+// Final ODB, spill log and plots of `RUN_NUMBER` as local files.
+fn offline_files() -> TempDir {
+    let files = TempDir::new().unwrap();
+    std::fs::write(files.path().join(format!("{RUN_NUMBER}.json")), FINAL_ODB).unwrap();
+    std::fs::write(files.path().join("spill_log.csv"), SPILL_LOG).unwrap();
+    for t in ["1.5-2.25", "5-6.5"] {
+        for (channel, name) in [(0, "SIS"), (1, "TPC")] {
+            std::fs::write(
+                files.path().join(format!("cb01-{channel}-{t}.pdf")),
+                format!("%PDF {name} {t}"),
+            )
+            .unwrap();
+        }
+    }
+
+    files
+}
+
+#[test]
+fn offline_section_enables_offline_mode() {
+    let server = MockDataHandler::start(Fixtures::default());
+    let files = offline_files();
+    let offline = format!(
+        r#"
+[offline]
+enabled = true
+final_odb = '{0}/{{run_number}}.json'
+spill_log = '{0}/spill_log.csv'
+plots_dir = '{0}'
+"#,
+        files.path().display()
+    );
+    let elogger = Elogger::with_config(&server, &offline, "");
+
+    let output = elogger.run(&[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let dir = elogger.output_dir();
+    assert!(entry_text(&dir).contains("CAT - Hot Dump 2"));
+    assert_eq!(attachment(&dir, 5), "%PDF TPC 5-6.5");
+    assert!(server.http_requests().is_empty());
+    assert!(server.requests().is_empty());
+}
+
+#[test]
+fn offline_run_is_not_cached() {
+    let server = MockDataHandler::start(Fixtures::default());
+    let files = offline_files();
+    let elogger = Elogger::new(&server, "");
+
+    let output = elogger.run(&[
+        "--final-odb",
+        &files
+            .path()
+            .join(format!("{RUN_NUMBER}.json"))
+            .to_string_lossy(),
+        "--spill-log",
+        &files.path().join("spill_log.csv").to_string_lossy(),
+        "--plots-dir",
+        &files.path().to_string_lossy(),
+    ]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(entry_text(&elogger.output_dir()).contains("CAT - Hot Dump 2"));
+
+    // Local files could be anything (e.g. an ODB edited by hand), so they must
+    // never be mistaken for data handler responses later on.
+    let cached = std::fs::read_dir(elogger.home.path().join("cache/data_handler"))
+        .map_or(0, |dir| dir.count());
+    assert_eq!(cached, 0);
+}
+
+#[test]
+fn second_run_uses_cache() {
+    let server = MockDataHandler::start(fixtures());
+    let elogger = Elogger::new(&server, "");
+
+    let output = elogger.run(&[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let first_text = entry_text(&elogger.output_dir());
+    let requests = server.requests().len();
+
+    std::fs::remove_dir_all(elogger.output_dir()).unwrap();
+    let output = elogger.run(&[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(entry_text(&elogger.output_dir()), first_text);
+    assert_eq!(server.requests().len(), requests);
+
+    // `--refresh` ignores the cache.
+    let output = elogger.run(&["--refresh"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(server.requests().len(), 2 * requests);
+}
+
+#[test]
+fn sequencer_summary_is_inline() {
+    let mut fixtures = fixtures();
+    fixtures.sequencer_csv.insert(
+        RUN_NUMBER,
+        Reply::Data(
+            "serial_number,midas_timestamp,header,xml\n\
+             7,1711272432,Mixing sequence,<xml/>\n"
+                .into(),
+        ),
+    );
+    let server = MockDataHandler::start(fixtures);
+    let elogger = Elogger::with_config(&server, r#"sequencer_headers = "summary""#, "");
+
+    let output = elogger.run(&["--no-cache"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let text = entry_text(&elogger.output_dir());
+    assert!(text.contains("Mixing sequence"));
+    assert!(text.contains("2024-03-24 10:27:12"));
+}
+
+#[test]
+fn sequencer_xml_is_attached() {
+    let mut fixtures = fixtures();
+    fixtures.sequencer_csv.insert(
+        RUN_NUMBER,
+        Reply::Data(
+            "serial_number,midas_timestamp,header,xml\n\
+             7,1711272432,Mixing sequence,<xml/>\n"
+                .into(),
+        ),
+    );
+    let server = MockDataHandler::start(fixtures);
+    let elogger = Elogger::with_config(&server, r#"sequencer_headers = "xml""#, "");
+
+    let output = elogger.run(&["--no-cache"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let dir = elogger.output_dir();
+    assert!(entry_text(&dir).contains("    7: elog:/1"));
+    assert_eq!(attachment(&dir, 1), "<xml/>");
+}
+
+#[test]
+fn record_times_are_shown_as_wall_clock() {
+    let server = MockDataHandler::start(fixtures());
+    let elogger = Elogger::with_config(
+        &server,
+        "record_times = { wall_clock = true, duration = true, gap = true }",
+        "",
+    );
+
+    let output = elogger.run(&["--no-cache"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let dir = elogger.output_dir();
+    let text = entry_text(&dir);
+    assert!(text.contains("CAT - Hot Dump 1 (11:27:13.500 to 11:27:14.250, 0.750 s long)"));
+    // The ignored record in between is not considered for the gap.
+    assert!(text.contains(
+        "CAT - Hot Dump 2 (11:27:17.000 to 11:27:18.500, 1.500 s long, 2.750 s after previous)"
+    ));
+
+    let summary = attachment(&dir, 1);
+    assert!(summary.contains("Duration"));
+    assert!(summary.contains("11:27:15.000"));
+    assert!(summary.contains("0.750 s"));
+    // Gaps in the summary include every record.
+    assert!(summary.contains("1.000 s"));
+}
+
+#[test]
+fn invalid_record_times_are_shown_as_is() {
+    let mut fixtures = fixtures();
+    fixtures.spill_log.insert(
+        RUN_NUMBER,
+        Reply::Data(
+            SPILL_LOG
+                .replace("Ignored,3.0", "Ignored,NaN")
+                .replace("Hot Dump 2,5.0,6.5", "Hot Dump 2,5.0,NaN")
+                .into(),
+        ),
+    );
+    let server = MockDataHandler::start(fixtures);
+    let elogger = Elogger::with_config(
+        &server,
+        "record_times = { wall_clock = true, duration = true }",
+        "",
+    );
+
+    let output = elogger.run(&["--no-cache"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let dir = elogger.output_dir();
+    assert!(entry_text(&dir).contains("CAT - Hot Dump 2 (11:27:17.000 to NaN, NaN s long)"));
+    assert!(attachment(&dir, 1).contains("NaN"));
+}
+
+#[test]
+fn summary_is_attached_in_every_format() {
+    let server = MockDataHandler::start(fixtures());
+    let elogger = Elogger::with_config(
+        &server,
+        r#"summary_formats = ["csv", "html", "markdown"]"#,
+        "",
+    );
+
+    let output = elogger.run(&["--no-cache"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let dir = elogger.output_dir();
+    assert!(entry_text(&dir).contains("Spill log summary: elog:/1, elog:/2, elog:/3"));
+    assert!(
+        attachment(&dir, 1).starts_with("Event,Start time,Stop time\nCAT - Hot Dump 1,1.5,2.25\n")
+    );
+    assert!(attachment(&dir, 2).contains("<th>Event</th><th>Start time</th><th>Stop time</th>"));
+    assert!(attachment(&dir, 3).contains("| CAT - Hot Dump 1 | 1.5"));
+    // Plots come after all the summaries.
+    assert_eq!(attachment(&dir, 4), "%PDF SIS");
+}
+
+#[test]
+fn running_run_is_provisional_and_not_cached() {
+    let mut fixtures = fixtures();
+    fixtures.final_odb.insert(
+        RUN_NUMBER,
+        Reply::Data(
+            FINAL_ODB
+                .replace(r#""State": 1"#, r#""State": 3"#)
+                .replace(
+                    r#""Start time binary": "0x66000000""#,
+                    r#""Start time": "Sun Mar 24 11:27:12 2024""#,
+                )
+                .into(),
+        ),
+    );
+    let server = MockDataHandler::start(fixtures);
+    let elogger = Elogger::new(&server, "");
+
+    let output = elogger.run(&[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let text = entry_text(&elogger.output_dir());
+    assert!(text.contains("Run started: 2024-03-24 at 11:27:12"));
+    assert!(text.contains("Run stopped: <STILL_RUNNING>"));
+
+    // Nothing about a running run is cached.
+    let requests = server.requests().len();
+    let output = elogger.run(&[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(server.requests().len(), 2 * requests);
+}
+
+// Rule that attaches one file of an external resource to each hot dump. It
+// takes precedence over the Chronobox table rule.
+fn external_resource_rule(base_path: &Path, extra: &str) -> String {
+    format!(
+        r#"
+[[rules]]
+sequencer_name = "cat"
+event_description = {{ glob = "Hot Dump *" }}
+config = {{ external_resources = [{{ base_path = '{}', include_attachment = true{extra} }}] }}
+"#,
+        base_path.display()
+    )
+}
+
+#[test]
+fn section_header_expands_captures() {
+    let server = MockDataHandler::start(fixtures());
+    let images = TempDir::new().unwrap();
+    let rule = external_resource_rule(
+        images.path(),
+        r#", header = 'Dump $1 (${1}): costs $$5, $5 or $other'"#,
+    );
+    let elogger = Elogger::with_config(&server, &rule, "");
+
+    let output = elogger.run(&["--no-cache"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let text = entry_text(&elogger.output_dir());
+    // Unknown references are left as they are.
+    assert!(text.contains("Dump 1 (1): costs $5, $5 or $other"));
+    assert!(text.contains("Dump 2 (2): costs $5, $5 or $other"));
+}
+
+#[test]
+fn external_resources_default_layout() {
+    let server = MockDataHandler::start(fixtures());
+    let images = TempDir::new().unwrap();
+    let day = images.path().join("2024/03/24");
+    std::fs::create_dir_all(&day).unwrap();
+    // The run starts at 11:27:12 and stops at 12:27:12 (Europe/Zurich).
+    for (name, contents) in [
+        ("1127_10.000.png", "before run"),
+        ("1127_14.000.png", "first"),
+        ("1127_18.500.png", "second"),
+        ("notes.txt", "not an image"),
+        ("1227_13.000.png", "after run"),
+    ] {
+        std::fs::write(day.join(name), contents).unwrap();
+    }
+    let elogger = Elogger::with_config(&server, &external_resource_rule(images.path(), ""), "");
+
+    let output = elogger.run(&["--no-cache"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let dir = elogger.output_dir();
+    assert_eq!(attachment(&dir, 2), "first");
+    assert_eq!(attachment(&dir, 3), "second");
+}
+
+#[test]
+fn external_resources_custom_layout() {
+    let server = MockDataHandler::start(fixtures());
+    let images = TempDir::new().unwrap();
+    // 1711276034 is 2 seconds after the start of the run.
+    for (name, contents) in [
+        ("cam_1711276034.jpg", "first"),
+        ("cam_1711276038.jpg", "second"),
+        ("cam_1711276038.png", "wrong extension"),
+    ] {
+        std::fs::write(images.path().join(name), contents).unwrap();
+    }
+    let rule = external_resource_rule(
+        images.path(),
+        r#", directory = "", file_pattern = 'cam_(?<timestamp>\d+)\.jpg'"#,
+    );
+    let elogger = Elogger::with_config(&server, &rule, "");
+
+    let output = elogger.run(&["--no-cache"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let dir = elogger.output_dir();
+    assert_eq!(attachment(&dir, 2), "first");
+    assert_eq!(attachment(&dir, 3), "second");
+}
+
+#[test]
+fn external_resources_reject_time_directory() {
+    let server = MockDataHandler::start(fixtures());
+    let images = TempDir::new().unwrap();
+    // Directories are per day; there is no hour to format.
+    let rule = external_resource_rule(images.path(), r#", directory = "%Y/%m/%d/%H""#);
+    let elogger = Elogger::with_config(&server, &rule, "");
+
+    let output = elogger.run(&["--no-cache"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("is not a valid date template"));
+}
+
+#[test]
+fn external_resources_are_matched_by_time() {
+    let server = MockDataHandler::start(fixtures());
+    let images = TempDir::new().unwrap();
+    let day = images.path().join("2024/03/24");
+    std::fs::create_dir_all(&day).unwrap();
+    // Nothing was taken during the second hot dump, and the last image is
+    // more than 10 seconds away from any record.
+    for (name, contents) in [("1127_14.000.png", "first"), ("1127_52.000.png", "stray")] {
+        std::fs::write(day.join(name), contents).unwrap();
+    }
+    let elogger = Elogger::with_config(&server, &external_resource_rule(images.path(), ""), "");
+
+    let output = elogger.run(&["--no-cache"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let dir = elogger.output_dir();
+    let text = entry_text(&dir);
+    assert_eq!(attachment(&dir, 2), "first");
+    let second_dump = text.split("CAT - Hot Dump 2").nth(1).unwrap();
+    assert!(second_dump.contains("<MISSING_ATTACHMENT>"));
+    assert!(text.contains("Unassigned resources:\n    11:27:52.000 "));
+    assert!(text.contains("1127_52.000.png"));
+}
+
+// Wait until `condition` holds (or panic after a few seconds).
+fn wait_for(what: &str, condition: impl Fn() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(20);
+    while !condition() {
+        assert!(Instant::now() < deadline, "timed out waiting for {what}");
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn watch_logs_runs_once_they_end() {
+    let mut fixtures = fixtures();
+    // The next run is still running.
+    let next_run = RUN_NUMBER + 1;
+    fixtures.ready_runs.insert(next_run);
+    fixtures.final_odb.insert(
+        next_run,
+        Reply::Data(FINAL_ODB.replace("\"State\": 1", "\"State\": 3").into()),
+    );
+    let server = MockDataHandler::start(fixtures);
+    let elog = MockElog::start();
+    let elogger = Elogger::with_config(&server, "[watch]\nattributes = {}", "");
+    elogger.submit_to(&elog);
+
+    let watch = |args: &[&str]| {
+        let mut command = elogger.command(&["watch", "--poll-interval", "1"]);
+        command
+            .args(args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        command.spawn().unwrap()
+    };
+    let stop = |mut child: Child| {
+        child.kill().unwrap();
+        child.wait_with_output().unwrap()
+    };
+
+    let child = watch(&["--from", &RUN_NUMBER.to_string()]);
+    wait_for("the entry to be submitted", || elog.requests().len() == 1);
+    let polled_next_run = || {
+        server
+            .requests()
+            .iter()
+            .any(|request| request["FinalOdb"]["run_number"] == next_run)
+    };
+    wait_for("the next run to be polled", polled_next_run);
+    // Give it the chance to (wrongly) log the running run.
+    std::thread::sleep(Duration::from_millis(1500));
+    let output = stop(child);
+
+    let requests = elog.requests();
+    assert_eq!(requests.len(), 1, "{}", stdout(&output));
+    assert!(requests[0].contains("CAT - Hot Dump 1"));
+    assert!(stdout(&output).contains("Waiting for run 12002 to end..."));
+    let data_dir = elogger.data_dir();
+    assert_eq!(
+        std::fs::read_to_string(data_dir.join("watch_last_run")).unwrap(),
+        "12001"
+    );
+    let ledger = std::fs::read_to_string(data_dir.join("ledger.csv")).unwrap();
+    assert!(ledger.contains("12001"));
+
+    // A restart resumes after the last logged run.
+    let child = watch(&[]);
+    std::thread::sleep(Duration::from_millis(1500));
+    let output = stop(child);
+    assert!(stdout(&output).contains("Waiting for run 12002 to end..."));
+    assert!(!stdout(&output).contains("run 12001"));
+    assert_eq!(elog.requests().len(), 1);
+}
+
+#[test]
+fn logged_runs_are_only_submitted_again_with_force() {
+    let server = MockDataHandler::start(fixtures());
+    let elog = MockElog::start();
+    let elogger = Elogger::new(&server, "");
+    elogger.submit_to(&elog);
+    let submit = |extra_args: &[&str]| {
+        elogger
+            .command(&["12001", "--new-thread", "--no-edit"])
+            .args(extra_args)
+            .output()
+            .unwrap()
+    };
+
+    let output = submit(&[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("Created elog entry for run 12001 with message ID 1"));
+
+    let output = submit(&[]);
+    assert!(!output.status.success());
+    let err = stderr(&output);
+    assert!(err.contains("run 12001 was already logged to `Test` as message ID 1"));
+    assert!(err.contains("(use `--force` to submit anyway)"));
+    assert_eq!(elog.requests().len(), 1);
+
+    // Dry runs only warn about it.
+    let output = elogger.run(&[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("Warning: run 12001 was already logged"));
+
+    let output = submit(&["--force"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("with message ID 2"));
+    assert_eq!(elog.requests().len(), 2);
+}
+
+#[test]
+fn logbook_profiles_override_the_defaults() {
+    let server = MockDataHandler::start(fixtures());
+    let elogger = Elogger::new(&server, "");
+    elogger.edit_config(
+        "[data_handler]",
+        r#"[elog.logbooks.Other]
+attributes = [
+    { name = "Author", source = "prompt" },
+    { name = "Run", source = "run_number" },
+]
+summary_formats = ["csv"]
+
+[[elog.logbooks.Other.rules]]
+sequencer_name = "atm"
+event_description = "Ignored"
+config = { chronobox_table = { channel_names = ["TPC"] } }
+
+[data_handler]"#,
+    );
+
+    let output = elogger.run(&["--no-cache", "--logbook", "Other", "--author", "Someone"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let printed = stdout(&output);
+    assert!(printed.contains("Logbook: Other\n"));
+    assert!(printed.contains("Author: Someone\nRun: 12001\n"));
+
+    let dir = elogger.output_dir();
+    let text = entry_text(&dir);
+    assert!(text.contains("ATM - Ignored"), "{text}");
+    assert!(!text.contains("Hot Dump"));
+    assert!(text.contains("Spill log summary: elog:/1\n"), "{text}");
+    assert!(attachment(&dir, 1).starts_with("Event,"));
+
+    // Everything else still uses the default profile.
+    let output = elogger.run(&["--no-cache"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("Logbook: Test\n"));
+    assert!(entry_text(&dir).contains("CAT - Hot Dump 1"));
+
+    let output = elogger.run(&["--no-cache", "--logbook", "Nope"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("no profile configured for logbook `Nope`"));
+}
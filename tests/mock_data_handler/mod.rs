@@ -0,0 +1,324 @@
+// In-process fake of the ALPHA-g data handler. It serves:
+//   - GET `/:run_number` (readiness)
+//   - websocket `/ws` (requests are answered with a download JWT or an error)
+//   - GET `/download/:jwt`
+// Every response comes from `Fixtures`, so tests control exactly what the
+// elogger sees (including errors, slow replies and malformed files).
+
+// Not every test crate uses everything in here.
+#![allow(dead_code)]
+
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Clone)]
+pub enum Reply {
+    // Downloadable content.
+    Data(Vec<u8>),
+    // `ServerResponse::Error` with the given message.
+    Error(String),
+    // Wait before replying (without sending anything).
+    Delayed(Duration, Box<Reply>),
+    // Close the websocket without replying.
+    Disconnect,
+}
+
+#[derive(Clone, Default)]
+pub struct Fixtures {
+    // Runs for which `/:run_number` is successful.
+    pub ready_runs: HashSet<u32>,
+    pub final_odb: HashMap<u32, Reply>,
+    pub spill_log: HashMap<u32, Reply>,
+    pub sequencer_csv: HashMap<u32, Reply>,
+    // Key is `(run_number, board_name, channel_number)`.
+    pub plots: HashMap<(u32, String, u8), Reply>,
+    // Same as `plots`, but only for a specific `t_min` (e.g. `1.5`). Takes
+    // precedence over `plots`.
+    pub timed_plots: HashMap<(u32, String, u8, String), Reply>,
+    // `ServerResponse::Text` messages sent before every reply.
+    pub progress_messages: Vec<String>,
+    // Prepare the replies to all requests on a connection at the same time
+    // (like the real data handler), instead of one request at a time. Replies
+    // are then sent as soon as they are ready, possibly out of order.
+    pub concurrent: bool,
+}
+
+#[derive(Default)]
+struct State {
+    fixtures: Fixtures,
+    downloads: HashMap<String, Vec<u8>>,
+    // Every websocket request received (as JSON).
+    requests: Vec<Value>,
+    // Websocket requests in the order in which their final reply was sent.
+    replies: Vec<Value>,
+    // Every request path received over plain HTTP.
+    http_requests: Vec<String>,
+}
+
+pub struct MockDataHandler {
+    port: u16,
+    state: Arc<Mutex<State>>,
+}
+
+impl MockDataHandler {
+    pub fn start(fixtures: Fixtures) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let state = Arc::new(Mutex::new(State {
+            fixtures,
+            ..Default::default()
+        }));
+
+        let server_state = Arc::clone(&state);
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let state = Arc::clone(&server_state);
+                std::thread::spawn(move || handle_connection(stream, &state));
+            }
+        });
+
+        Self { port, state }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    // Make `/:run_number` successful from now on.
+    pub fn make_ready(&self, run_number: u32) {
+        self.state
+            .lock()
+            .unwrap()
+            .fixtures
+            .ready_runs
+            .insert(run_number);
+    }
+
+    pub fn requests(&self) -> Vec<Value> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    pub fn replies(&self) -> Vec<Value> {
+        self.state.lock().unwrap().replies.clone()
+    }
+
+    pub fn http_requests(&self) -> Vec<String> {
+        self.state.lock().unwrap().http_requests.clone()
+    }
+}
+
+// Path of the request line (e.g. `/ws` in `GET /ws HTTP/1.1`), without
+// consuming anything from the stream.
+fn peek_path(stream: &TcpStream) -> Option<String> {
+    let mut buf = [0; 1024];
+    loop {
+        let n = stream.peek(&mut buf).ok()?;
+        if n == 0 {
+            return None;
+        }
+        let head = String::from_utf8_lossy(&buf[..n]);
+        if let Some((line, _)) = head.split_once("\r\n") {
+            return line.split_whitespace().nth(1).map(String::from);
+        }
+        if n == buf.len() {
+            return None;
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+fn handle_connection(stream: TcpStream, state: &Arc<Mutex<State>>) {
+    let Some(path) = peek_path(&stream) else {
+        return;
+    };
+    if path == "/ws" {
+        handle_websocket(stream, state);
+    } else {
+        handle_http(stream, &path, state);
+    }
+}
+
+fn handle_http(stream: TcpStream, path: &str, state: &Mutex<State>) {
+    let mut reader = BufReader::new(stream);
+    // Requests from the client never have a body.
+    let mut line = String::new();
+    while reader.read_line(&mut line).is_ok_and(|n| n > 0) && line != "\r\n" {
+        line.clear();
+    }
+
+    let (status, body) = {
+        let mut state = state.lock().unwrap();
+        state.http_requests.push(path.to_string());
+
+        if let Some(jwt) = path.strip_prefix("/download/") {
+            match state.downloads.get(jwt) {
+                Some(bytes) => ("200 OK", bytes.clone()),
+                None => ("404 Not Found", Vec::new()),
+            }
+        } else {
+            match path[1..].parse::<u32>() {
+                Ok(run) if state.fixtures.ready_runs.contains(&run) => ("200 OK", Vec::new()),
+                _ => ("404 Not Found", Vec::new()),
+            }
+        }
+    };
+
+    let mut stream = reader.into_inner();
+    let _ = write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    let _ = stream.write_all(&body);
+}
+
+// Messages to send over the websocket. `None` closes the connection.
+type Outbox = mpsc::Sender<Option<String>>;
+
+fn handle_websocket(stream: TcpStream, state: &Arc<Mutex<State>>) {
+    let Ok(mut ws) = tungstenite::accept(stream) else {
+        return;
+    };
+    // Replies prepared by other threads are sent in between reads.
+    let _ = ws
+        .get_ref()
+        .set_read_timeout(Some(Duration::from_millis(5)));
+    let (outbox, inbox) = mpsc::channel();
+
+    loop {
+        match ws.read() {
+            Ok(tungstenite::Message::Text(text)) => {
+                let msg: Value = serde_json::from_str(&text).unwrap();
+                let concurrent = state.lock().unwrap().fixtures.concurrent;
+                if concurrent {
+                    let (state, outbox) = (Arc::clone(state), outbox.clone());
+                    std::thread::spawn(move || respond(&msg, &state, &outbox));
+                } else {
+                    respond(&msg, state, &outbox);
+                }
+            }
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(err))
+                if matches!(
+                    err.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) => {}
+            Err(_) => return,
+        }
+
+        while let Ok(message) = inbox.try_recv() {
+            let Some(message) = message else {
+                close_gracefully(ws.get_mut());
+                return;
+            };
+            if ws.send(tungstenite::Message::Text(message)).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+// Dropping a socket with unread data resets the connection, and the client
+// could then lose replies that it received but didn't read yet. Wait for the
+// client to close its end instead.
+fn close_gracefully(stream: &mut TcpStream) {
+    let _ = stream.shutdown(Shutdown::Write);
+    let deadline = Instant::now() + Duration::from_secs(2);
+    let mut buf = [0; 1024];
+    while Instant::now() < deadline {
+        match stream.read(&mut buf) {
+            Ok(0) => return,
+            Ok(_) => {}
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) => {}
+            Err(_) => return,
+        }
+    }
+}
+
+// Queue all the messages in reply to a single websocket request.
+fn respond(msg: &Value, state: &Mutex<State>, outbox: &Outbox) {
+    let context = msg["context"].as_str().unwrap();
+    let request = &msg["request"];
+
+    let (reply, progress_messages) = {
+        let mut state = state.lock().unwrap();
+        state.requests.push(request.clone());
+        (
+            find_reply(&state.fixtures, request),
+            state.fixtures.progress_messages.clone(),
+        )
+    };
+
+    let send = |response: Value| {
+        let message = json!({ "service": "", "context": context, "response": response });
+        let _ = outbox.send(Some(message.to_string()));
+    };
+    for text in progress_messages {
+        send(json!({ "Text": text }));
+    }
+
+    let mut reply = reply;
+    while let Reply::Delayed(delay, inner) = reply {
+        std::thread::sleep(delay);
+        reply = *inner;
+    }
+    let mut state = state.lock().unwrap();
+    let response = match reply {
+        Reply::Data(bytes) => {
+            let jwt = format!("jwt{}", state.downloads.len());
+            state.downloads.insert(jwt.clone(), bytes);
+            json!({ "DownloadJWT": jwt })
+        }
+        Reply::Error(err) => json!({ "Error": err }),
+        Reply::Disconnect => {
+            let _ = outbox.send(None);
+            return;
+        }
+        Reply::Delayed(..) => unreachable!(),
+    };
+    state.replies.push(request.clone());
+    send(response);
+}
+
+// Requests are serialized as e.g. `{"FinalOdb": {"run_number": 1}}`.
+fn find_reply(fixtures: &Fixtures, request: &Value) -> Reply {
+    let (variant, fields) = request.as_object().unwrap().iter().next().unwrap();
+    let run_number = fields["run_number"].as_u64().unwrap() as u32;
+
+    let reply = match variant.as_str() {
+        "FinalOdb" => fixtures.final_odb.get(&run_number),
+        "SpillLog" => fixtures.spill_log.get(&run_number),
+        "SequencerCsv" => fixtures.sequencer_csv.get(&run_number),
+        "ChronoboxPlot" => {
+            let args = &fields["args"];
+            let board_name = args["board_name"].as_str().unwrap().to_string();
+            let channel_number = args["channel_number"].as_u64().unwrap() as u8;
+            fixtures
+                .timed_plots
+                .get(&(
+                    run_number,
+                    board_name.clone(),
+                    channel_number,
+                    args["t_min"].to_string(),
+                ))
+                .or_else(|| {
+                    fixtures
+                        .plots
+                        .get(&(run_number, board_name, channel_number))
+                })
+        }
+        _ => None,
+    };
+
+    reply
+        .cloned()
+        .unwrap_or_else(|| Reply::Error(format!("no fixture for {request}")))
+}
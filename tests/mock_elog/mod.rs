@@ -0,0 +1,91 @@
+// Fake ELOG server that accepts every entry it receives, and redirects to the
+// new entry like the real server does (message IDs start at 1).
+
+// Not every test crate uses everything in here.
+#![allow(dead_code)]
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+
+pub struct MockElog {
+    port: u16,
+    // Every request received (headers and body).
+    requests: Arc<Mutex<Vec<String>>>,
+}
+
+impl MockElog {
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let server_requests = Arc::clone(&requests);
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let mut reader = BufReader::new(stream);
+                let request = read_request(&mut reader);
+                let message_id = {
+                    let mut requests = server_requests.lock().unwrap();
+                    requests.push(request);
+                    requests.len()
+                };
+                let _ = write!(
+                    reader.into_inner(),
+                    "HTTP/1.1 302 Found\r\nLocation: /Test/{message_id}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                );
+            }
+        });
+
+        Self { port, requests }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+// Headers and body of an HTTP request (the body can be chunked).
+pub fn read_request(reader: &mut impl BufRead) -> String {
+    let mut request = String::new();
+    let mut content_length = 0;
+    let mut chunked = false;
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let lowercase = line.to_lowercase();
+        if let Some(length) = lowercase.strip_prefix("content-length:") {
+            content_length = length.trim().parse().unwrap();
+        }
+        chunked |= lowercase.starts_with("transfer-encoding: chunked");
+        request.push_str(&line);
+        if line == "\r\n" {
+            break;
+        }
+    }
+
+    let mut body = Vec::new();
+    if chunked {
+        loop {
+            let mut size = String::new();
+            reader.read_line(&mut size).unwrap();
+            let size = usize::from_str_radix(size.trim(), 16).unwrap();
+            let mut chunk = vec![0; size + 2];
+            reader.read_exact(&mut chunk).unwrap();
+            if size == 0 {
+                break;
+            }
+            body.extend_from_slice(&chunk[..size]);
+        }
+    } else {
+        body.resize(content_length, 0);
+        reader.read_exact(&mut body).unwrap();
+    }
+    request.push_str(&String::from_utf8_lossy(&body));
+
+    request
+}
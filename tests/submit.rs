@@ -0,0 +1,142 @@
+// Submit entries to a minimal fake ELOG server. Each server answers a single
+// request with a canned response, and hands back the request it received.
+
+mod mock_elog;
+
+use alpha_g_elogger::config::ElogConfig;
+use alpha_g_elogger::elog::ElogEntry;
+use alpha_g_elogger::submit::{submit_entry, SubmitError};
+use mock_elog::read_request;
+use std::collections::HashMap;
+use std::io::{BufReader, Write};
+use std::net::TcpListener;
+use std::thread::JoinHandle;
+
+struct MockElog {
+    port: u16,
+    request: JoinHandle<String>,
+}
+
+impl MockElog {
+    // `response` is everything after the status line (headers and body).
+    fn start(status: &str, response: &str) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let reply = format!("HTTP/1.1 {status}\r\nConnection: close\r\n{response}");
+
+        let request = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let request = read_request(&mut reader);
+            let _ = reader.into_inner().write_all(reply.as_bytes());
+
+            request
+        });
+
+        Self { port, request }
+    }
+
+    fn config(&self) -> ElogConfig {
+        ElogConfig {
+            host: String::from("127.0.0.1"),
+            port: self.port,
+            logbook: String::from("Test"),
+            logbooks: HashMap::new(),
+        }
+    }
+
+    fn request(self) -> String {
+        self.request.join().unwrap()
+    }
+}
+
+fn entry() -> ElogEntry {
+    let attachment = tempfile::Builder::new()
+        .suffix(".txt")
+        .tempfile()
+        .unwrap()
+        .into_temp_path()
+        .keep()
+        .unwrap();
+    std::fs::write(&attachment, "attachment contents").unwrap();
+
+    ElogEntry {
+        text: String::from("Run started: elog:/1"),
+        attachments: vec![attachment],
+    }
+}
+
+fn attributes() -> Vec<(String, String)> {
+    vec![(String::from("Run number"), String::from("12001"))]
+}
+
+#[test]
+fn redirect_returns_message_id() {
+    let elog = MockElog::start(
+        "302 Found",
+        "Location: http://127.0.0.1/Test/42\r\nContent-Length: 0\r\n\r\n",
+    );
+
+    let message_id = submit_entry(&entry(), &attributes(), Some(7), &elog.config()).unwrap();
+    assert_eq!(message_id, 42);
+
+    let request = elog.request();
+    assert!(request.starts_with("POST /Test/ HTTP/1.1"));
+    for expected in [
+        "Run started: elog:/1",
+        "name=\"Run_number\"",
+        "name=\"reply_to\"",
+        "name=\"attfile0\"",
+        "attachment contents",
+    ] {
+        assert!(request.contains(expected), "missing `{expected}`");
+    }
+}
+
+#[test]
+fn failed_login_redirect_is_rejected() {
+    let elog = MockElog::start(
+        "302 Found",
+        "Location: http://127.0.0.1/Test/?fail=1\r\nContent-Length: 0\r\n\r\n",
+    );
+
+    let err = submit_entry(&entry(), &attributes(), None, &elog.config()).unwrap_err();
+    assert!(
+        matches!(&err, SubmitError::Rejected(reason) if reason == "invalid user name or password"),
+        "{err:?}"
+    );
+}
+
+#[test]
+fn error_page_is_rejected() {
+    let body = "<html><body>\n<b>Error: Attribute <i>Author</i> not supplied.</b>\n</body></html>";
+    let elog = MockElog::start(
+        "200 OK",
+        &format!("Content-Length: {}\r\n\r\n{body}", body.len()),
+    );
+
+    let err = submit_entry(&entry(), &attributes(), None, &elog.config()).unwrap_err();
+    assert!(
+        matches!(
+            &err,
+            SubmitError::Rejected(reason)
+                if reason == "missing required attribute (Attribute Author not supplied.)"
+        ),
+        "{err:?}"
+    );
+}
+
+#[test]
+fn unknown_logbook_is_rejected() {
+    let body = "<title>ELOG Logbook Selection</title>";
+    let elog = MockElog::start(
+        "200 OK",
+        &format!("Content-Length: {}\r\n\r\n{body}", body.len()),
+    );
+
+    let err = submit_entry(&entry(), &attributes(), None, &elog.config()).unwrap_err();
+    assert!(
+        matches!(&err, SubmitError::Rejected(reason) if reason == "no such logbook"),
+        "{err:?}"
+    );
+}